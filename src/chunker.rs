@@ -0,0 +1,314 @@
+use simplelog::*;
+use std::path::Path;
+use std::sync::Once;
+use std::collections::HashMap;
+use globset::{GlobBuilder, GlobMatcher};
+
+use crate::code::{CodeChunker, language, language_name};
+use crate::markdown::MarkdownChunker;
+use crate::fragments::{Fragment, FragmentSize, Tokens};
+
+// Chunking passes to make room for the headers, which depend on where the
+// fragments end up
+const HEADER_PASSES: usize = 3;
+
+// The old `[chunking] code = [...]` format is reported once per run
+static LEGACY_CHUNKING: Once = Once::new();
+
+// --| Chunker ------------------------
+// --|---------------------------------
+/// A strategy to split a document into fragments. The document is tokenized
+/// up front with the size of its rule, so a strategy only chooses where the
+/// fragments start and end. New strategies implement this trait and are
+/// added to `ChunkingRule::chunker`.
+pub trait Chunker {
+  fn chunk(&self, document: &str, tokens: &Tokens) -> Vec<Fragment>;
+}
+
+// --| Create Fragments ---------------
+// --|---------------------------------
+/// Split a document with the strategy of the chunking rule that matches its
+/// project relative path, and prepend the rule's header to each fragment.
+/// The header is embedded with the fragment, so its tokens are taken from
+/// the fragment size. A header that leaves no room for text is left out.
+/// Fragments past the model limit are reported.
+pub fn create_fragments(document: &str, relative: &str, settings: &config::Config) -> Vec<Fragment> {
+  let rule = chunking_rule(relative, settings);
+  let mut tokens = Tokens::new(document, &rule.size, settings);
+  info!("Chunking {} with {}", relative, rule.strategy);
+  info!("Token total: {}", tokens.offsets.len());
+  info!("Max tokens: {}, overlap: {}", tokens.max_tokens, tokens.overlap);
+
+  let chunker = rule.chunker(relative);
+  let budget = tokens.max_tokens;
+  let mut fragments = with_headers(&rule, relative, chunker.chunk(document, &tokens));
+
+  for _ in 0..HEADER_PASSES {
+    let header_tokens = fragments.iter().map(|fragment| tokens.count_text(&fragment.header)).max().unwrap_or(0);
+    if header_tokens <= budget - tokens.max_tokens { break; }
+
+    if header_tokens >= budget {
+      warn!("The header of {} has {} tokens and leaves no room for text, leaving it out", relative, header_tokens);
+      tokens.resize(budget, &rule.size);
+      fragments = chunker.chunk(document, &tokens);
+      for fragment in fragments.iter_mut() { fragment.header.clear(); }
+      break;
+    }
+
+    debug!("Reserving {} tokens for the header of {}", header_tokens, relative);
+    tokens.resize(budget - header_tokens, &rule.size);
+    fragments = with_headers(&rule, relative, chunker.chunk(document, &tokens));
+  }
+
+  tokens.measure(&mut fragments);
+  fragments
+}
+
+/// Prepend the rendered header of the rule to the header a strategy set
+fn with_headers(rule: &ChunkingRule, relative: &str, mut fragments: Vec<Fragment>) -> Vec<Fragment> {
+  if !rule.header.is_empty() {
+    for fragment in fragments.iter_mut() {
+      fragment.header = format!("{}{}", render_header(rule, relative, fragment), fragment.header);
+    }
+  }
+  fragments
+}
+
+// --| Context Header -----------------
+// --|---------------------------------
+/// Fill in the header template of a rule for a fragment. Placeholders are
+/// `{path}`, `{file_name}`, `{extension}`, `{language}`, `{symbol}` and
+/// `{heading}`. Lines whose placeholders are all empty are left out.
+pub fn render_header(rule: &ChunkingRule, relative: &str, fragment: &Fragment) -> String {
+  let path = Path::new(relative);
+  let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+  let metadata = |key: &str| fragment.metadata.get(key).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+
+  let values = [
+    ("{path}", relative.to_string()),
+    ("{file_name}", path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string()),
+    ("{extension}", extension.to_string()),
+    ("{language}", rule.language.clone().or(language_name(extension).map(|l| l.to_string())).unwrap_or(extension.to_string())),
+    ("{symbol}", metadata("symbol")),
+    ("{heading}", metadata("heading_path")),
+  ];
+
+  let lines = rule.header.lines().filter_map(|line| {
+    let used = values.iter().filter(|(key, _)| line.contains(key)).collect::<Vec<_>>();
+    if !used.is_empty() && used.iter().all(|(_, value)| value.is_empty()) { return None; }
+
+    Some(used.iter().fold(line.to_string(), |line, (key, value)| line.replace(key, value)))
+  }).collect::<Vec<String>>();
+
+  match lines.is_empty() {
+    true => String::new(),
+    false => format!("{}\n\n", lines.join("\n")),
+  }
+}
+
+// --| Chunking Rule ------------------
+// --|---------------------------------
+/// A `[chunking."<pattern>"]` table. The pattern is a file extension (`md`),
+/// a glob matched against the file name (`*.md`), or a glob matched against
+/// the relative path when it contains a slash (`docs/**/*.md`). When several
+/// patterns match, the longest one wins.
+#[derive(Debug, Clone)]
+pub struct ChunkingRule {
+  pub pattern: String,
+  pub strategy: String,
+  pub size: FragmentSize,
+  pub language: Option<String>,
+  pub breadcrumbs: bool,
+  pub header: String,
+}
+
+impl ChunkingRule {
+  /// Token windows with the `[database]` size and header, used when no rule matches
+  pub fn from_settings(settings: &config::Config) -> Self {
+    Self {
+      pattern: String::new(),
+      strategy: "window".to_string(),
+      size: FragmentSize::from_settings(settings),
+      language: None,
+      breadcrumbs: false,
+      header: settings.get_str("database.header").unwrap_or_default(),
+    }
+  }
+
+  fn from_table(pattern: &str, table: HashMap<String, config::Value>, settings: &config::Config) -> Self {
+    let mut rule = Self::from_settings(settings);
+    rule.pattern = pattern.to_string();
+
+    let get = |key: &str| table.get(key).cloned();
+    if let Some(strategy) = get("strategy").and_then(|v| v.into_str().ok()) { rule.strategy = strategy; }
+    if let Some(max_tokens) = get("max_tokens").and_then(|v| v.into_int().ok()) { rule.size.max_tokens = max_tokens; }
+    if let Some(overlap) = get("overlap").and_then(|v| v.into_str().ok()) { rule.size.overlap = overlap; }
+    if let Some(breadcrumbs) = get("breadcrumbs").and_then(|v| v.into_bool().ok()) { rule.breadcrumbs = breadcrumbs; }
+    if let Some(header) = get("header").and_then(|v| v.into_str().ok()) { rule.header = header; }
+    rule.language = get("language").and_then(|v| v.into_str().ok());
+
+    rule
+  }
+
+  /// Extensions are matched as `*.<extension>`
+  fn glob(&self) -> String {
+    match self.pattern.contains(|c| "*?[{/.".contains(c)) {
+      true => self.pattern.clone(),
+      false => format!("*.{}", self.pattern),
+    }
+  }
+
+  fn matcher(&self) -> Option<GlobMatcher> {
+    match GlobBuilder::new(&self.glob()).literal_separator(true).build() {
+      Ok(glob) => Some(glob.compile_matcher()),
+      Err(err) => {
+        warn!("Invalid chunking pattern {}: {}", self.pattern, err);
+        None
+      }
+    }
+  }
+
+  fn matches(&self, relative: &str) -> bool {
+    let matcher = match self.matcher() {
+      Some(matcher) => matcher,
+      None => return false,
+    };
+
+    match self.pattern.contains('/') {
+      true => matcher.is_match(relative),
+      false => Path::new(relative).file_name().map(|name| matcher.is_match(name)).unwrap_or(false),
+    }
+  }
+
+  /// The strategy of this rule, token windows if it is unknown
+  pub fn chunker(&self, relative: &str) -> Box<dyn Chunker> {
+    match self.strategy.as_str() {
+      "window" => Box::new(TokenWindow),
+      "sentence" => Box::new(TextChunker { unit: TextUnit::Sentence }),
+      "paragraph" => Box::new(TextChunker { unit: TextUnit::Paragraph }),
+      "markdown" => Box::new(MarkdownChunker { breadcrumbs: self.breadcrumbs }),
+      "file" => Box::new(WholeFile),
+      "code" => {
+        let extension = Path::new(relative).extension().and_then(|e| e.to_str()).unwrap_or_default();
+        match language(self.language.as_deref().unwrap_or(extension)) {
+          Some(language) => Box::new(CodeChunker { language }),
+          None => {
+            warn!("No syntax support for {}, splitting it into token windows", relative);
+            Box::new(TokenWindow)
+          }
+        }
+      },
+      strategy => {
+        warn!("Unknown chunking strategy {} for {}, splitting into token windows", strategy, self.pattern);
+        Box::new(TokenWindow)
+      }
+    }
+  }
+}
+
+/// The most specific `[chunking]` rule matching a relative path. A list of
+/// extensions under a strategy name, `code = ["rs", "py"]`, is the format
+/// from before per pattern rules and still applies that strategy to them.
+pub fn chunking_rule(relative: &str, settings: &config::Config) -> ChunkingRule {
+  let tables = settings.get_table("chunking").unwrap_or_default();
+  let mut rules: Vec<ChunkingRule> = vec![];
+
+  for (key, value) in tables {
+    if let Ok(table) = value.clone().into_table() {
+      rules.push(ChunkingRule::from_table(&key, table, settings));
+      continue;
+    }
+
+    match value.into_array() {
+      Ok(extensions) => {
+        LEGACY_CHUNKING.call_once(|| {
+          warn!("[chunking] {} = [...] is deprecated, use a [chunking.\"<extension>\"] table with strategy = \"{}\" instead", key, key);
+        });
+
+        for extension in extensions.into_iter().filter_map(|extension| extension.into_str().ok()) {
+          let mut rule = ChunkingRule::from_settings(settings);
+          rule.pattern = extension;
+          rule.strategy = key.clone();
+          rule.breadcrumbs = settings.get_bool("chunking.breadcrumbs").unwrap_or(false);
+          rules.push(rule);
+        }
+      },
+      Err(_) => debug!("Ignoring [chunking] {}, expected a table", key),
+    }
+  }
+
+  rules.retain(|rule| rule.matches(relative));
+
+  rules.sort_by(|a, b| b.pattern.len().cmp(&a.pattern.len()).then(a.pattern.cmp(&b.pattern)));
+  rules.into_iter().next().unwrap_or_else(|| ChunkingRule::from_settings(settings))
+}
+
+// --| Token Window -------------------
+// --|---------------------------------
+/// Fixed windows of `max_tokens`, sharing `overlap` tokens
+pub struct TokenWindow;
+
+impl Chunker for TokenWindow {
+  fn chunk(&self, document: &str, tokens: &Tokens) -> Vec<Fragment> {
+    tokens.windows(document, 0, document.len())
+  }
+}
+
+// --| Text Chunker -------------------
+// --|---------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextUnit { Sentence, Paragraph }
+
+/// Whole sentences or paragraphs, as many as fit into `max_tokens`. Ones
+/// that are too large on their own are split into windows.
+pub struct TextChunker {
+  pub unit: TextUnit,
+}
+
+impl TextChunker {
+  /// Paragraphs start after a blank line, sentences after `.`, `!` or `?`
+  /// followed by whitespace
+  fn boundaries(&self, document: &str) -> Vec<usize> {
+    let mut boundaries = vec![0];
+    let mut pending = false;
+    let mut newlines = 0;
+    let mut previous: Option<char> = None;
+
+    for (i, c) in document.char_indices() {
+      if c.is_whitespace() {
+        if c == '\n' { newlines += 1; }
+        if newlines >= 2 { pending = true; }
+        if self.unit == TextUnit::Sentence && matches!(previous, Some('.' | '!' | '?')) { pending = true; }
+      } else {
+        if pending && i > *boundaries.last().unwrap() { boundaries.push(i); }
+        pending = false;
+        newlines = 0;
+      }
+      previous = Some(c);
+    }
+
+    boundaries
+  }
+}
+
+impl Chunker for TextChunker {
+  fn chunk(&self, document: &str, tokens: &Tokens) -> Vec<Fragment> {
+    let boundaries = self.boundaries(document);
+    let blocks = boundaries.iter().enumerate()
+      .map(|(i, start)| (*start, boundaries.get(i + 1).copied().unwrap_or(document.len())))
+      .collect::<Vec<(usize, usize)>>();
+
+    tokens.merge(document, &blocks)
+  }
+}
+
+// --| Whole File ---------------------
+// --|---------------------------------
+/// The whole file as a single fragment, reported if the model truncates it
+pub struct WholeFile;
+
+impl Chunker for WholeFile {
+  fn chunk(&self, document: &str, tokens: &Tokens) -> Vec<Fragment> {
+    vec![tokens.fragment(document, 0, document.len())]
+  }
+}
@@ -1,4 +1,4 @@
-use clap::{arg, Arg, Command};
+use clap::{arg, value_parser, Arg, Command};
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Build application cli 
@@ -20,7 +20,7 @@ pub fn cli() -> Command {
       .value_delimiter(',').use_value_delimiter(true))
 
     .arg( // --| Included Extensions ------------
-      arg!(matcher: --matcher <List> "The list of glob patterns to match include/exclude against (takes precedence over all match types)")
+      arg!(matcher: --matcher <List> "The list of gitignore style patterns to include, or exclude with a leading ! (takes precedence over extensions and ignored)")
       .value_delimiter(',').use_value_delimiter(true))
 
     .arg( // --| Included Directories -----------
@@ -31,6 +31,21 @@ pub fn cli() -> Command {
       arg!(ignored: -i --ignored <List> "The list of directories to ignore within the project root directory")
       .value_delimiter(',').use_value_delimiter(true))
 
+    .arg( // --| Ignore Files -------------------
+      arg!(no_gitignore: --"no-gitignore" "Index files matched by .gitignore files"))
+
+    .arg(
+      arg!(no_ignore_file: --"no-ignore-file" "Index files matched by .ignore files"))
+
+    .arg(
+      arg!(no_vectorizerignore: --"no-vectorizerignore" "Index files matched by .vectorizerignore files"))
+
+    .arg( // --| Git Changes --------------------
+      arg!(since: --since <Rev> "Only index files changed since a git revision, and delete the points of files removed since"))
+
+    .arg(
+      arg!(staged: --staged "Only index files with staged changes, and delete the points of staged removals"))
+
     .arg( // --| Collection Name ----------------
       arg!(collection: -c --collection <Name> "The name of the collection in which to upload/create"))
 
@@ -50,20 +65,57 @@ pub fn cli() -> Command {
     .arg( // --| Max Tokens ---------------------
       arg!(token_max: -t --tokenmax <Size> "The maximum amount of tokens per fragment"))
 
+    .arg( // --| Fragment Overlap ---------------
+      arg!(overlap: --overlap <Size> "The tokens shared by neighbouring fragments, as a count or percentage (ex: 32 or 15%)"))
+
+    .arg( // --| Batch Size ---------------------
+      arg!(batch_size: -b --batchsize <Size> "The amount of fragments to embed per batch (default: 32)"))
+
+    .arg( // --| Output Format ------------------
+      arg!(output: -o --output <Format> "The output format to print results in (default: text)")
+      .value_parser(["text", "json", "jsonl"]))
+
     .arg( // --| Log level ----------------------
       arg!(level: -L --level <Name> "The log level to use")
       .value_parser(["error", "warn", "info", "debug"]))
 
     .subcommand( // --| Index and upload --------
-     Command::new("upload").long_flag("upload").about("Index and upload files"))
+     Command::new("upload").long_flag("upload").about("Index and upload files")
+     .arg(arg!(force: -f --force "Upload every file, even if unchanged since the last upload"))
+     .arg(arg!(prune: --prune "Delete points of files that no longer exist in the project"))
+     .arg(git_rev()))
+
+    .subcommand( // --| Watch and upload --------
+     Command::new("watch").long_flag("watch").about("Upload changes, then keep uploading files as they change")
+     .arg(arg!(force: -f --force "Upload every file on start, even if unchanged since the last upload"))
+     .arg(arg!(prune: --prune "Delete points of files removed while not watching, on start"))
+     .arg(Arg::new("debounce").long("debounce").help("The milliseconds without changes before uploading them (default: 500)")
+       .value_parser(value_parser!(u64))))
 
     .subcommand( // --| Index Only --------------
-      Command::new("index").long_flag("index").about("Index files"))
+      Command::new("index").long_flag("index").about("Index files")
+      .arg(arg!(explain: --explain "List every candidate path with the reason it is included or excluded, and the fragments per included file"))
+      .arg(git_rev()))
+
+    .subcommand( // --| Model Daemon ------------
+      Command::new("serve-model").about("Keep the model loaded and embed for other vectorizer runs over a local socket"))
 
     .subcommand( // --| Test Connection ---------
       Command::new("test").long_flag("test").about("Test Connection to Qdrant"))
 
     .subcommand( // --| Search -------------------
-      Command::new("search").long_flag("search").about("Perform a test search on uploaded data")
-      .arg(Arg::new("term").long("term").short('T').help("The search term to use")))
+      Command::new("search").long_flag("search").about("Search the uploaded data")
+      .arg(Arg::new("term").long("term").short('T').help("The search term to use"))
+      .arg(Arg::new("limit").long("limit").short('n').help("The maximum amount of results (default: 10)")
+        .value_parser(value_parser!(u64)))
+      .arg(Arg::new("offset").long("offset").help("The amount of results to skip")
+        .value_parser(value_parser!(u64)))
+      .arg(Arg::new("score_threshold").long("score-threshold").short('s').help("The minimum score of a result")
+        .value_parser(value_parser!(f32))))
+}
+
+/// Only for `upload` and `index`, watching a revision would index the
+/// working tree under its namespace
+fn git_rev() -> Arg {
+  arg!(git_rev: --"git-rev" <Rev> "Index the files of a git revision, read from the repository instead of the working tree")
 }
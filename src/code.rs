@@ -0,0 +1,265 @@
+use simplelog::*;
+use serde_json::Value;
+use tree_sitter::{Language, Node, Parser};
+
+use crate::chunker::Chunker;
+use crate::fragments::{Fragment, Tokens};
+
+// Node kinds that document the item after them
+const COMMENT_KINDS: [&str; 3] = ["comment", "line_comment", "block_comment"];
+
+// --| Code Language ------------------
+// --|---------------------------------
+// Separates the names of nested symbols
+const SYMBOL_SEPARATOR: &str = " > ";
+
+/// Supported grammars by extension or name: Rust, Python, TypeScript, Go and Lua
+pub fn language(language: &str) -> Option<Language> {
+  match language {
+    "rs" | "rust" => Some(tree_sitter_rust::language()),
+    "py" | "pyi" | "python" => Some(tree_sitter_python::language()),
+    "ts" | "mts" | "cts" | "typescript" => Some(tree_sitter_typescript::language_typescript()),
+    "tsx" => Some(tree_sitter_typescript::language_tsx()),
+    "go" => Some(tree_sitter_go::language()),
+    "lua" => Some(tree_sitter_lua::language()),
+    _ => None,
+  }
+}
+
+/// Name of the language of a file extension, for display
+pub fn language_name(extension: &str) -> Option<&'static str> {
+  match extension {
+    "rs" => Some("rust"),
+    "py" | "pyi" => Some("python"),
+    "ts" | "mts" | "cts" | "tsx" => Some("typescript"),
+    "js" | "mjs" | "cjs" | "jsx" => Some("javascript"),
+    "go" => Some("go"),
+    "lua" => Some("lua"),
+    "md" | "markdown" => Some("markdown"),
+    _ => None,
+  }
+}
+
+// --| Code Chunker -------------------
+// --|---------------------------------
+/// Split source code on its top-level items (functions, impls, classes,
+/// modules), keeping comments with the item below them. Small neighbouring
+/// items share a fragment up to `max_tokens`. Items that are too large are
+/// split on their own children, and into token windows when that is not
+/// enough. The names of the items in a fragment, with the items enclosing
+/// them, are stored as `symbol` in its metadata (e.g. `Manifest > load`).
+pub struct CodeChunker {
+  pub language: Language,
+}
+
+impl Chunker for CodeChunker {
+  fn chunk(&self, document: &str, tokens: &Tokens) -> Vec<Fragment> {
+    let mut parser = Parser::new();
+    let tree = match parser.set_language(self.language).ok().and_then(|_| parser.parse(document, None)) {
+      Some(tree) => tree,
+      None => {
+        warn!("Could not parse document, splitting it into token windows");
+        return tokens.windows(document, 0, document.len());
+      }
+    };
+
+    let mut spans: Vec<Span> = Vec::new();
+    split_node(document, tokens, tree.root_node(), 0, document.len(), &[], &mut spans);
+
+    // --| Merge small items, window the ones that could not be split
+    let ranges = spans.iter().map(|span| (span.start, span.end)).collect::<Vec<(usize, usize)>>();
+    let mut fragments = tokens.merge(document, &ranges);
+
+    for fragment in fragments.iter_mut() {
+      if let Some(symbol) = fragment_symbol(&spans, fragment) {
+        fragment.metadata.insert("symbol".to_owned(), Value::String(symbol));
+      }
+    }
+
+    fragments
+  }
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+struct Span {
+  start: usize,
+  end: usize,
+  symbol: Vec<String>,
+}
+
+/// Split `start..end` on the children of `node`, descending into children
+/// that do not fit into a fragment. `symbol` holds the names of the items
+/// enclosing `node`.
+fn split_node(document: &str, tokens: &Tokens, node: Node, start: usize, end: usize, symbol: &[String], spans: &mut Vec<Span>) {
+  for (piece_start, piece_end, item) in pieces(document, node, start, end) {
+    let mut item_symbol = symbol.to_vec();
+    item_symbol.extend(item.and_then(|item| symbol_name(item, document)));
+
+    match item {
+      Some(item) if item.named_child_count() > 0 && tokens.count(piece_start, piece_end) > tokens.max_tokens => {
+        split_node(document, tokens, item, piece_start, piece_end, &item_symbol, spans)
+      },
+      _ => spans.push(Span { start: piece_start, end: piece_end, symbol: item_symbol }),
+    }
+  }
+}
+
+/// The symbol of the span a fragment starts in, or the first one within it
+fn fragment_symbol(spans: &[Span], fragment: &Fragment) -> Option<String> {
+  spans.iter()
+    .filter(|span| span.end > fragment.start_byte && span.start < fragment.end_byte)
+    .find(|span| !span.symbol.is_empty())
+    .map(|span| span.symbol.join(SYMBOL_SEPARATOR))
+}
+
+/// The name of a function, type, class or module, looking through exports,
+/// decorators and Go type declarations. Impls are named after their type.
+fn symbol_name(node: Node, document: &str) -> Option<String> {
+  let text = |node: Node| document.get(node.byte_range()).map(|text| text.to_string());
+
+  if let Some(name) = node.child_by_field_name("name") { return text(name); }
+
+  if node.kind() == "impl_item" {
+    let type_name = node.child_by_field_name("type").and_then(text)?;
+    return match node.child_by_field_name("trait").and_then(text) {
+      Some(trait_name) => Some(format!("{} for {}", trait_name, type_name)),
+      None => Some(type_name),
+    };
+  }
+
+  for field in ["definition", "declaration"] {
+    if let Some(inner) = node.child_by_field_name(field) { return symbol_name(inner, document); }
+  }
+
+  let mut cursor = node.walk();
+  let spec = node.named_children(&mut cursor).find(|child| child.kind() == "type_spec");
+  spec.and_then(|spec| symbol_name(spec, document))
+}
+
+/// Byte ranges covering `start..end`, one per named child of `node`. Each
+/// range begins at the start of the child's line and runs to the next one,
+/// comments directly above a child are part of its range.
+fn pieces<'tree>(document: &str, node: Node<'tree>, start: usize, end: usize) -> Vec<(usize, usize, Option<Node<'tree>>)> {
+  let mut boundaries: Vec<(usize, Option<Node>)> = vec![(start, None)];
+  let mut previous: Option<Node> = None;
+  let mut cursor = node.walk();
+
+  for child in node.named_children(&mut cursor) {
+    let attached = previous
+      .map(|p| is_comment(&p) && child.start_position().row <= p.end_position().row + 1)
+      .unwrap_or(false);
+    previous = Some(child);
+
+    let last = boundaries.last_mut().unwrap();
+    let boundary = line_start(document, child.start_byte());
+
+    if attached || boundary <= last.0 || boundary >= end {
+      last.1 = Some(child);
+    } else {
+      boundaries.push((boundary, Some(child)));
+    }
+  }
+
+  boundaries.iter().enumerate()
+    .map(|(i, (boundary, item))| {
+      let next = boundaries.get(i + 1).map(|b| b.0).unwrap_or(end);
+      (*boundary, next, *item)
+    })
+    .collect()
+}
+
+fn is_comment(node: &Node) -> bool {
+  COMMENT_KINDS.contains(&node.kind())
+}
+
+/// Byte position of the start of the line containing `position`
+fn line_start(document: &str, position: usize) -> usize {
+  document[..position].rfind('\n').map(|i| i + 1).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fragments::FragmentSize;
+
+  fn chunk(extension: &str, document: &str, max_tokens: i64) -> Vec<String> {
+    let mut settings = config::Config::default();
+    // --| Words are counted without the model tokenizer
+    settings.set("model.tokenizer", "missing/tokenizer.json").unwrap();
+
+    let tokens = Tokens::new(document, &FragmentSize { max_tokens, ..Default::default() }, &settings);
+    let chunker = CodeChunker { language: language(extension).unwrap() };
+    chunker.chunk(document, &tokens).into_iter().map(|f| f.text).collect()
+  }
+
+  #[test]
+  fn rust_splits_on_items() {
+    let document = "// Adds one\nfn add_one(value: i32) -> i32 {\n  value + 1\n}\n\nstruct Point {\n  x: i32,\n  y: i32,\n}\n";
+    assert_eq!(chunk("rs", document, 12), vec![
+      "// Adds one\nfn add_one(value: i32) -> i32 {\n  value + 1\n}\n\n",
+      "struct Point {\n  x: i32,\n  y: i32,\n}\n",
+    ]);
+
+    // --| Small items share a fragment
+    assert_eq!(chunk("rs", document, 40), vec![document]);
+  }
+
+  #[test]
+  fn python_splits_on_items() {
+    let document = "# Adds one\ndef add_one(value):\n    return value + 1\n\nclass Point:\n    def __init__(self, x, y):\n        self.x = x\n        self.y = y\n";
+    assert_eq!(chunk("py", document, 12), vec![
+      "# Adds one\ndef add_one(value):\n    return value + 1\n\n",
+      "class Point:\n    def __init__(self, x, y):\n        self.x = x\n        self.y = y\n",
+    ]);
+  }
+
+  #[test]
+  fn typescript_splits_on_items() {
+    let document = "// Adds one\nfunction addOne(value: number): number {\n  return value + 1;\n}\n\ninterface Point {\n  x: number;\n  y: number;\n}\n";
+    assert_eq!(chunk("ts", document, 12), vec![
+      "// Adds one\nfunction addOne(value: number): number {\n  return value + 1;\n}\n\n",
+      "interface Point {\n  x: number;\n  y: number;\n}\n",
+    ]);
+  }
+
+  #[test]
+  fn go_splits_on_items() {
+    let document = "package main\n\n// Adds one\nfunc addOne(value int) int {\n\treturn value + 1\n}\n\ntype Point struct {\n\tX int\n\tY int\n}\n";
+    assert_eq!(chunk("go", document, 12), vec![
+      "package main\n\n// Adds one\nfunc addOne(value int) int {\n\treturn value + 1\n}\n\n",
+      "type Point struct {\n\tX int\n\tY int\n}\n",
+    ]);
+  }
+
+  #[test]
+  fn lua_splits_on_items() {
+    let document = "-- Adds one\nlocal function add_one(value)\n  return value + 1\nend\n\nlocal point = { x = 1, y = 2 }\n";
+    assert_eq!(chunk("lua", document, 12), vec![
+      "-- Adds one\nlocal function add_one(value)\n  return value + 1\nend\n\n",
+      "local point = { x = 1, y = 2 }\n",
+    ]);
+  }
+
+  #[test]
+  fn large_items_split_on_their_children() {
+    let document = "class Point:\n    def __init__(self, x, y):\n        self.x = x\n        self.y = y\n";
+    assert_eq!(chunk("py", document, 8), vec![
+      "class Point:\n    def __init__(self, x, y):\n",
+      "        self.x = x\n        self.y = y\n",
+    ]);
+  }
+
+  #[test]
+  fn items_without_children_fall_back_to_windows() {
+    let document = "# one two three four five six seven eight\n";
+    assert_eq!(chunk("py", document, 3), vec!["# one two ", "three four five ", "six seven eight\n"]);
+  }
+
+  #[test]
+  fn languages_by_extension_or_name() {
+    assert!(language("rs").is_some());
+    assert!(language("python").is_some());
+    assert!(language("c").is_none());
+  }
+}
@@ -44,6 +44,12 @@ pub fn get_system_config(name: &str) -> PathBuf {
     path
 }
 
+/// The directory that holds settings, logs and manifests, without touching it
+pub fn get_config_dir(name: &str) -> PathBuf {
+    let home = env::var("HOME").expect("Failed to get home dir");
+    PathBuf::from(home).join(".config").join(name.to_lowercase())
+}
+
 pub fn default_settings(settings: PathBuf) -> PathBuf {
     let settings_path = Path::new(&settings).join("settings.toml");
 
@@ -52,15 +58,30 @@ pub fn default_settings(settings: PathBuf) -> PathBuf {
 [indexer]
 log_level = "warn"
 project_file = true # Create a project settings override file
-extensions   = []   # List of file extensions to index
+extensions   = []   # List of file extensions to index (default: all)
 directories  = []   # List of directories to include within the project root
 ignored      = []   # List of directories to ignore within the project root
+namespace    = ""   # Project namespace for document ids (default: project root path)
+gitignore    = true # Skip files matched by .gitignore files
+ignore_file  = true # Skip files matched by .ignore files
+vectorizerignore = true # Skip files matched by .vectorizerignore files
+max_file_size = 0   # Skip files larger than this many bytes (0: no limit)
 
 [database]
+backend      = "qdrant" # Database backend to use: qdrant or local
 url          = ""   # URL to the database
 collection   = ""   # Name of the collection to create
 max_tokens   = 0    # Maximum tokens per fragment when splitting documents
+overlap      = 0    # Tokens shared by neighbouring fragments, as a count or a percentage ("15%")
 metadata     = ""   # Additional Metadata to add, in json format
+header       = ""   # Template prepended to the embedded text, ex: "File: {path}\nSymbol: {symbol}"
+
+[chunking."*"]       # Chunking per extension or glob, ex: [chunking."*.md"] strategy = "markdown"
+strategy     = "window" # window, sentence, paragraph, markdown, code or file
+
+[model]
+backend      = "rust-bert" # Embedding backend to use
+daemon       = true  # Embed with a running serve-model daemon, when there is one for this model
 "##;
 
     let toml = settings_toml.replace("{{PATH}}", &settings.to_str().unwrap());
@@ -75,15 +96,26 @@ pub fn default_project_settings(settings: PathBuf) -> PathBuf {
     let mut settings_file = File::create(&settings_path).unwrap();
     let settings_toml = r##"
 [indexer]
-extensions   = []   # List of file extensions to index
+extensions   = []   # List of file extensions to index (default: all)
 directories  = []   # List of directories to include within the project root
 ignored      = []   # List of directories to ignore within the project root
+namespace    = ""   # Project namespace for document ids (default: project root path)
+gitignore    = true # Skip files matched by .gitignore files
+ignore_file  = true # Skip files matched by .ignore files
+vectorizerignore = true # Skip files matched by .vectorizerignore files
+max_file_size = 0   # Skip files larger than this many bytes (0: no limit)
 
 [database]
+backend      = "qdrant" # Database backend to use: qdrant or local
 url          = ""   # URL to the database
 collection   = ""   # Name of the collection to create
 max_tokens   = 0    # Maximum tokens per fragment when splitting documents
+overlap      = 0    # Tokens shared by neighbouring fragments, as a count or a percentage ("15%")
 metadata     = ""   # Additional Metadata to add, in json format
+header       = ""   # Template prepended to the embedded text, ex: "File: {path}\nSymbol: {symbol}"
+
+[chunking."*"]       # Chunking per extension or glob, ex: [chunking."*.md"] strategy = "markdown"
+strategy     = "window" # window, sentence, paragraph, markdown, code or file
 "##;
 
     settings_file.write_all(settings_toml.as_bytes()).unwrap();
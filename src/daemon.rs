@@ -0,0 +1,249 @@
+use simplelog::*;
+use anyhow::{anyhow, Error};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use path_absolutize::Absolutize;
+
+use crate::SETTINGS;
+use crate::model::ModelInfo;
+use crate::configuration::get_config_dir;
+use crate::upload::{UploadOptions, UploadSummary};
+
+#[cfg(unix)]
+use crate::{database, upload, vectorize::Model};
+#[cfg(unix)]
+use std::{fs, sync::Arc};
+#[cfg(unix)]
+use tokio::sync::Mutex;
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream as ClientStream;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+// --| Jobs ---------------------------
+// --|---------------------------------
+/// A request to the daemon, one JSON object per line. Searches send the
+/// text of their query as an `embed` job. An `upload` job carries the
+/// settings of the project, the daemon indexes and uploads it with them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "job", rename_all = "snake_case")]
+pub enum Job {
+  Embed { texts: Vec<String> },
+  Upload { settings: serde_json::Value, force: bool, prune: bool },
+  Info,
+}
+
+/// The answer to a job, one JSON object per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reply {
+  Embeddings { model: ModelInfo, embeddings: Vec<Vec<f32>> },
+  Upload(UploadSummary),
+  Info(ModelInfo),
+  Error(String),
+}
+
+// --| Socket -------------------------
+// --|---------------------------------
+/// Location of the socket of the daemon serving the configured model:
+/// ~/.config/vectorizer/daemon/<model>.sock. Each model configuration gets
+/// its own daemon, so a client never embeds with a different model.
+pub fn socket_path(settings: &config::Config) -> PathBuf {
+  let key = format!("{}:{}:{}:{}",
+    settings.get_str("model.backend").unwrap_or("rust-bert".to_string()),
+    settings.get_bool("model.local").unwrap_or(false),
+    settings.get_str("model.location").unwrap_or_default(),
+    settings.get_int("model.max_sequence_length").unwrap_or(0),
+  );
+  let name = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, key.as_bytes()).to_string();
+
+  get_config_dir("vectorizer").join("daemon").join(format!("{}.sock", name))
+}
+
+/// The socket of a running daemon for the configured model, unless
+/// `model.daemon` is off
+#[cfg(unix)]
+pub fn running(settings: &config::Config) -> Option<PathBuf> {
+  if !settings.get_bool("model.daemon").unwrap_or(true) { return None; }
+
+  let socket = socket_path(settings);
+  ClientStream::connect(&socket).ok().map(|_| socket)
+}
+
+#[cfg(not(unix))]
+pub fn running(_settings: &config::Config) -> Option<PathBuf> {
+  None
+}
+
+// --| Client -------------------------
+// --|---------------------------------
+/// Embed texts with the daemon's model, returned in the same order
+pub async fn embed(socket: &Path, texts: Vec<String>) -> Result<(ModelInfo, Vec<Vec<f32>>), Error> {
+  match tokio::task::block_in_place(|| request(socket, &Job::Embed { texts }))? {
+    Reply::Embeddings { model, embeddings } => Ok((model, embeddings)),
+    Reply::Error(err) => Err(anyhow!("Model daemon: {}", err)),
+    _ => Err(anyhow!("Unexpected reply from the model daemon")),
+  }
+}
+
+/// Index and upload the project with the daemon's model. The settings are
+/// sent along, with the project path made absolute for the daemon.
+pub async fn upload(socket: &Path, options: &UploadOptions) -> Result<UploadSummary, Error> {
+  let settings = {
+    let mut settings = SETTINGS.read().unwrap().clone();
+    let project = PathBuf::from(settings.get_str("indexer.project")?).absolutize()?.to_path_buf();
+    settings.set("indexer.project", project.to_str().unwrap())?;
+    settings.try_into::<serde_json::Value>()?
+  };
+
+  let job = Job::Upload { settings, force: options.force, prune: options.prune };
+  match tokio::task::block_in_place(|| request(socket, &job))? {
+    Reply::Upload(summary) => Ok(summary),
+    Reply::Error(err) => Err(anyhow!("Model daemon: {}", err)),
+    _ => Err(anyhow!("Unexpected reply from the model daemon")),
+  }
+}
+
+pub async fn info(socket: &Path) -> Result<ModelInfo, Error> {
+  match tokio::task::block_in_place(|| request(socket, &Job::Info))? {
+    Reply::Info(info) => Ok(info),
+    Reply::Error(err) => Err(anyhow!("Model daemon: {}", err)),
+    _ => Err(anyhow!("Unexpected reply from the model daemon")),
+  }
+}
+
+#[cfg(unix)]
+fn request(socket: &Path, job: &Job) -> Result<Reply, Error> {
+  let mut stream = ClientStream::connect(socket)?;
+  let mut json = serde_json::to_vec(job)?;
+  json.push(b'\n');
+  stream.write_all(&json)?;
+
+  let mut line = String::new();
+  BufReader::new(stream).read_line(&mut line)?;
+  Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(not(unix))]
+fn request(_socket: &Path, _job: &Job) -> Result<Reply, Error> {
+  Err(anyhow!("The model daemon needs Unix domain sockets"))
+}
+
+// --| Serve Model --------------------
+// --|---------------------------------
+/// Load the configured model once and embed the jobs sent to its socket
+/// until interrupted. Uploads run one at a time, each with the settings of
+/// its project.
+#[cfg(unix)]
+pub async fn serve() -> Result<(), Error> {
+  let socket = socket_path(&SETTINGS.read().unwrap());
+
+  // --| A socket file without a daemon behind it is left from a crash
+  if socket.exists() {
+    if ClientStream::connect(&socket).is_ok() {
+      return Err(anyhow!("A model daemon is already running at {}", socket.display()));
+    }
+    fs::remove_file(&socket)?;
+  }
+  if let Some(parent) = socket.parent() { fs::create_dir_all(parent)?; }
+
+  // --| The model is loaded before accepting jobs, clients never wait on it
+  let (handle, model) = Model::spawn();
+  let model_info = model.info().await?;
+
+  let listener = UnixListener::bind(&socket)?;
+  info!("Serving {} at {}", model_info.name, socket.display());
+  let model = Arc::new(model);
+  let uploads = Arc::new(Mutex::new(()));
+
+  // --| Without the model runner no job can be served, so the daemon stops with it
+  let mut runner = tokio::task::spawn_blocking(move || handle.join());
+  let mut result = Ok(());
+
+  loop {
+    tokio::select! {
+      accepted = listener.accept() => {
+        let (stream, _) = accepted?;
+        let (model, uploads) = (model.clone(), uploads.clone());
+        tokio::spawn(async move {
+          if let Err(err) = handle_connection(stream, &model, &uploads).await { warn!("Model daemon: {}", err); }
+        });
+      },
+      stopped = &mut runner => {
+        result = match stopped {
+          Ok(Ok(Err(err))) => Err(anyhow!("The model runner stopped: {}", err)),
+          Ok(Ok(Ok(()))) => Err(anyhow!("The model runner stopped")),
+          _ => Err(anyhow!("The model runner panicked")),
+        };
+        break;
+      },
+      _ = tokio::signal::ctrl_c() => break,
+    }
+  }
+
+  info!("Stopping the model daemon");
+  drop(listener);
+  fs::remove_file(&socket)?;
+  result
+}
+
+#[cfg(not(unix))]
+pub async fn serve() -> Result<(), Error> {
+  Err(anyhow!("The model daemon needs Unix domain sockets"))
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+#[cfg(unix)]
+async fn handle_connection(stream: UnixStream, model: &Model, uploads: &Mutex<()>) -> Result<(), Error> {
+  let (reader, mut writer) = stream.into_split();
+  let mut lines = AsyncBufReader::new(reader).lines();
+
+  while let Some(line) = lines.next_line().await? {
+    let reply = match serde_json::from_str::<Job>(&line) {
+      Ok(job) => run_job(model, uploads, job).await.unwrap_or_else(|err| Reply::Error(err.to_string())),
+      Err(err) => Reply::Error(format!("Invalid job: {}", err)),
+    };
+
+    let mut json = serde_json::to_vec(&reply)?;
+    json.push(b'\n');
+    writer.write_all(&json).await?;
+  }
+
+  Ok(())
+}
+
+#[cfg(unix)]
+async fn run_job(model: &Model, uploads: &Mutex<()>, job: Job) -> Result<Reply, Error> {
+  match job {
+    Job::Embed { texts } => {
+      debug!("Embedding {} texts", texts.len());
+      let embeddings = model.embed(texts).await?;
+      Ok(Reply::Embeddings { model: model.info().await?, embeddings })
+    },
+    Job::Upload { settings, force, prune } => {
+      let _upload = uploads.lock().await;
+      let settings = config::Config::try_from(&settings)?;
+      info!("Uploading {}", settings.get_str("indexer.project").unwrap_or_default());
+
+      // --| Indexing and the database read the global settings, the daemon's
+      // --| own are put back once the upload is done
+      let previous = std::mem::replace(&mut *SETTINGS.write().unwrap(), settings);
+      let result = upload_project(model, UploadOptions { force, prune }).await;
+      *SETTINGS.write().unwrap() = previous;
+
+      Ok(Reply::Upload(result?))
+    },
+    Job::Info => Ok(Reply::Info(model.info().await?)),
+  }
+}
+
+#[cfg(unix)]
+async fn upload_project(model: &Model, options: UploadOptions) -> Result<UploadSummary, Error> {
+  let database = database::connect().await?;
+  upload::upload_with(database.as_ref(), Some(model), options).await
+}
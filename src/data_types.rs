@@ -6,6 +6,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use simplelog::*;
 
+use crate::fragments::Fragment;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ModelLocation { Local, Remote }
 
@@ -18,7 +20,16 @@ pub struct Arguments {
   pub project: Option<String>,
   pub metadata: Option<String>,
   pub token_max: Option<usize>,
+  pub overlap: Option<String>,
+  pub batch_size: Option<usize>,
   pub log_level: Option<String>,
+  pub output: Option<String>,
+  pub gitignore: bool,
+  pub ignore_file: bool,
+  pub vectorizerignore: bool,
+  pub since: Option<String>,
+  pub staged: bool,
+  pub git_rev: Option<String>,
   pub collection: Option<String>,
   pub matcher: Option<Vec<String>>,
   pub ignored: Option<Vec<String>>,
@@ -38,9 +49,18 @@ impl Arguments {
       metadata: None,
       location: None,
       log_level: None,
+      output: None,
+      gitignore: true,
+      ignore_file: true,
+      vectorizerignore: true,
+      since: None,
+      staged: false,
+      git_rev: None,
       extensions: None,
       directories: None,
       location_path: None,
+      batch_size: None,
+      overlap: None,
       token_max: Some(256),
       remote: Some("L12".to_string()),
       collection: Some("document_chunks".to_string()),
@@ -52,29 +72,41 @@ impl Arguments {
     args.dburl = matches.get_one::<String>("dburl").cloned();
     args.project = matches.get_one::<String>("project").cloned(); 
     args.log_level = matches.get_one::<String>("level").cloned();
+    args.output = matches.get_one::<String>("output").cloned();
     args.metadata = matches.get_one::<String>("metadata").cloned();
+    args.gitignore = !matches.get_flag("no_gitignore");
+    args.ignore_file = !matches.get_flag("no_ignore_file");
+    args.vectorizerignore = !matches.get_flag("no_vectorizerignore");
+    args.since = matches.get_one::<String>("since").cloned();
+    args.staged = matches.get_flag("staged");
+    args.git_rev = matches.subcommand()
+      .and_then(|(_, sub_matches)| sub_matches.try_get_one::<String>("git_rev").ok().flatten())
+      .cloned();
     args.collection = matches.get_one::<String>("collection").cloned();
 
     args.token_max  = matches.get_one::<String>("token_max").cloned()
       .map(|s| s.parse::<usize>().unwrap());
 
-    // --| Matcher takes precedence over extensions, ignored, and directories
-    // --| If present, ignore other match type arguments
+    args.overlap = matches.get_one::<String>("overlap").cloned();
+
+    args.batch_size = matches.get_one::<String>("batch_size").cloned()
+      .map(|s| s.parse::<usize>().unwrap());
+
+    // --| Matcher rules take precedence over extensions and ignored, see walker::FileWalker
     if let Some(values) = matches.get_many::<String>("matcher") {
       args.matcher = Some(values.map(|s| s.to_string()).collect());
-    } 
-    else {
-      if let Some(values) = matches.get_many::<String>("extensions") {
-        args.extensions = Some(values.map(|s| s.to_string()).collect());
-      }
+    }
 
-      if let Some(values) = matches.get_many::<String>("ignored") {
-        args.ignored = Some(values.map(|s| s.to_string()).collect());
-      }
+    if let Some(values) = matches.get_many::<String>("extensions") {
+      args.extensions = Some(values.map(|s| s.to_string()).collect());
+    }
 
-      if let Some(values) = matches.get_many::<String>("directories") {
-        args.directories = Some(values.map(|s| s.to_string()).collect());
-      }
+    if let Some(values) = matches.get_many::<String>("ignored") {
+      args.ignored = Some(values.map(|s| s.to_string()).collect());
+    }
+
+    if let Some(values) = matches.get_many::<String>("directories") {
+      args.directories = Some(values.map(|s| s.to_string()).collect());
     }
 
     if let Some(value) = matches.get_one::<String>("local"){
@@ -96,12 +128,25 @@ impl Arguments {
     if let Some(values) = &self.extensions  { let _ = &settings.set("indexer.extensions", values.clone()).unwrap(); }
     if let Some(values) = &self.directories { let _ = &settings.set("indexer.directories", values.clone()).unwrap(); }
 
+    // --| Ignore files are respected unless turned off in the settings or with a flag
+    if !self.gitignore        { let _ = &settings.set("indexer.gitignore", false).unwrap(); }
+    if !self.ignore_file      { let _ = &settings.set("indexer.ignore_file", false).unwrap(); }
+    if !self.vectorizerignore { let _ = &settings.set("indexer.vectorizerignore", false).unwrap(); }
+
+    // --| Limit the run to a git diff, see git::GitSelection, or read a revision
+    if let Some(value)  = &self.since       { let _ = &settings.set("git.since", value.clone()).unwrap(); }
+    if self.staged                          { let _ = &settings.set("git.staged", true).unwrap(); }
+    if let Some(value)  = &self.git_rev     { let _ = &settings.set("git.rev", value.clone()).unwrap(); }
+
     if let Some(value)  = &self.dburl       { let _ = &settings.set("database.url", value.clone()).unwrap(); }
     if let Some(value)  = &self.metadata    { let _ = &settings.set("database.metadata", value.clone()).unwrap(); }
     if let Some(value)  = &self.collection  { let _ = &settings.set("database.collection", value.clone()).unwrap(); }
     if let Some(value)  = &self.token_max   { let _ = &settings.set("database.max_tokens", value.clone().to_string()).unwrap(); }
+    if let Some(value)  = &self.overlap     { let _ = &settings.set("database.overlap", value.clone()).unwrap(); }
+    if let Some(value)  = &self.batch_size  { let _ = &settings.set("model.batch_size", value.clone().to_string()).unwrap(); }
     
     if let Some(values) = &self.matcher     { let _ = &settings.set("matcher.rules", values.clone()).unwrap(); }
+    if let Some(value)  = &self.output      { let _ = &settings.set("output.format", value.clone()).unwrap(); }
 
     if let Some(value) = &self.location{
       let location = value.clone();
@@ -158,6 +203,8 @@ pub struct EmbeddedDocuments {
   pub collection: String,
   pub documents: Vec<EmbeddedDocument>,
   pub metadata: HashMap<String, Value>,
+  pub model: String,
+  pub dimensions: usize,
 }
 
 impl EmbeddedDocuments {
@@ -166,6 +213,8 @@ impl EmbeddedDocuments {
       documents: Vec::new(),
       metadata: HashMap::new(),
       collection: String::new(),
+      model: String::new(),
+      dimensions: 0,
     }
   }
 }
@@ -178,6 +227,7 @@ pub struct EmbeddedDocument {
   pub document_id: String,
   pub name: String,
   pub text: String,
+  pub range: TextRange,
   pub embeddings: Vec<f32>, 
   pub metadata: HashMap<String, Value>,
 }
@@ -189,6 +239,19 @@ pub struct Documents {
   pub collection: String,
   pub documents: Vec<Document>,
   pub metadata: HashMap<String, Value>,
+  pub unchanged: Vec<String>,
+  pub skipped: Vec<String>,
+  pub errors: Vec<FileError>,
+  /// Only some files of the project were indexed, see `git::GitSelection`
+  pub partial: bool,
+  pub removed: Vec<String>,
+}
+
+/// A file that could not be indexed, reported once the run is done
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileError {
+  pub path: String,
+  pub error: String,
 }
 
 impl Documents {
@@ -197,6 +260,11 @@ impl Documents {
       documents: Vec::new(),
       metadata: HashMap::new(),
       collection: String::new(),
+      unchanged: Vec::new(),
+      skipped: Vec::new(),
+      errors: Vec::new(),
+      partial: false,
+      removed: Vec::new(),
     }
   }
 
@@ -216,35 +284,73 @@ impl Documents {
 
 // --| Document -----------------------
 // --|---------------------------------
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Document {
   pub id: String,
   pub name: String,
+  pub path: String,
+  pub hash: String,
+  #[serde(skip_serializing)]
   pub text: String,
   pub metadata: HashMap<String, Value>,
   pub fragments: Vec<DocumentFragment>,
 }
 
 impl Document {
-  pub fn add_fragment(&mut self, fragment_text: String, index: usize) {
-    let mut fragment = DocumentFragment::new();
-    fragment.document_id = self.id.clone();
-    fragment.id = format!("{}_{}", self.id, index);
-    fragment.name = self.name.clone();
-    fragment.text = fragment_text;
-    fragment.metadata = self.metadata.clone();
-    self.fragments.push(fragment);
+  pub fn add_fragment(&mut self, fragment: &Fragment, index: usize) {
+    let mut document_fragment = DocumentFragment::new();
+    document_fragment.document_id = self.id.clone();
+    document_fragment.id = format!("{}_{}", self.id, index);
+    document_fragment.name = self.name.clone();
+    document_fragment.text = fragment.text.clone();
+    document_fragment.header = fragment.header.clone();
+    document_fragment.range = TextRange {
+      start_line: self.line_at(fragment.start_byte),
+      end_line: self.line_at(fragment.end_byte.saturating_sub(1).max(fragment.start_byte)),
+      start_byte: fragment.start_byte,
+      end_byte: fragment.end_byte,
+    };
+    document_fragment.metadata = self.metadata.clone();
+    document_fragment.metadata.extend(fragment.metadata.clone());
+    document_fragment.metadata.insert("tokens".to_owned(), Value::from(fragment.tokens));
+    document_fragment.metadata.insert("overlap".to_owned(), Value::from(fragment.overlap));
+    if fragment.truncated {
+      document_fragment.metadata.insert("truncated".to_owned(), Value::Bool(true));
+    }
+    self.fragments.push(document_fragment);
+  }
+
+  /// 1-based line number of a byte position in the text
+  fn line_at(&self, position: usize) -> usize {
+    let position = position.min(self.text.len());
+    self.text.as_bytes()[..position].iter().filter(|b| **b == b'\n').count() + 1
   }
 }
 
+// --| TextRange ----------------------
+// --|---------------------------------
+/// Location of a fragment in its file: 1-based inclusive lines and a byte
+/// range, so hits can be opened in an editor or re-read from disk
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct TextRange {
+  pub start_line: usize,
+  pub end_line: usize,
+  pub start_byte: usize,
+  pub end_byte: usize,
+}
+
 // --| DocumentFragment ---------------
 // --|---------------------------------
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct DocumentFragment{
   pub id: String,
   pub document_id: String,
   pub name: String,
   pub text: String,
+  #[serde(skip_serializing_if = "String::is_empty")]
+  pub header: String,
+  #[serde(flatten)]
+  pub range: TextRange,
   pub metadata: HashMap<String, Value>,
 }
 
@@ -255,16 +361,24 @@ impl DocumentFragment {
       document_id: String::new(),
       name: String::new(),
       text: String::new(),
+      header: String::new(),
+      range: TextRange::default(),
       metadata: HashMap::new(),
     }
   }
 
+  /// The text given to the model, the header is not stored with the point
+  pub fn embedding_text(&self) -> String {
+    format!("{}{}", self.header, self.text)
+  }
+
   pub fn to_embedded(&self, embeddings: Vec<f32>) -> EmbeddedDocument {
     EmbeddedDocument {
       id: self.id.clone(),
       document_id: self.document_id.clone(),
       name: self.name.clone(),
       text: self.text.clone(),
+      range: self.range,
       metadata: self.metadata.clone(),
       embeddings,
     }
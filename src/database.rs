@@ -1,7 +1,214 @@
-use anyhow::Error;
-use crate::data_types::EmbeddedDocuments;
+use simplelog::*;
+use serde_json::Value;
+use serde::Serialize;
+use async_trait::async_trait;
+use anyhow::{anyhow, Error};
+use std::collections::HashMap;
+use chrono::{Local, DateTime};
 
-// TODO: This is a reminder to implement additional databases via traits
-trait Database {
-  fn upsert(&self, documents: EmbeddedDocuments) -> Result<(), Error>;
+use crate::SETTINGS;
+use crate::qdrant::QdrantDatabase;
+use crate::local_store::LocalStore;
+use crate::data_types::{EmbeddedDocuments, EmbeddedDocument, TextRange};
+
+// --| Database DataTypes -------------
+// --|---------------------------------
+/// Payload fields and the values they must be equal to
+pub type PayloadFilter = HashMap<String, Value>;
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchQuery {
+  pub collection: String,
+  pub vector: Vec<f32>,
+  pub limit: u64,
+  pub offset: Option<u64>,
+  pub score_threshold: Option<f32>,
+  pub filter: Option<PayloadFilter>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+  pub id: String,
+  pub score: f32,
+  pub payload: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredPoint {
+  pub id: String,
+  pub payload: HashMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CollectionInfo {
+  pub name: String,
+  pub points: u64,
+  pub dimensions: u64,
+}
+
+// --| Database -----------------------
+// --|---------------------------------
+/// A vector store. New backends implement this trait and are added to
+/// `connect`, which picks one using the `database.backend` setting.
+/// Points are addressed by fragment id, backends map them to their own ids.
+#[async_trait]
+pub trait Database: Send + Sync {
+  async fn list_collections(&self) -> Result<Vec<String>, Error>;
+
+  async fn create_collection(&self, collection: &str, dimensions: usize) -> Result<(), Error>;
+
+  /// Returns None if the collection does not exist
+  async fn collection_info(&self, collection: &str) -> Result<Option<CollectionInfo>, Error>;
+
+  async fn drop_collection(&self, collection: &str) -> Result<(), Error>;
+
+  async fn upsert(&self, documents: EmbeddedDocuments) -> Result<(), Error>;
+
+  async fn delete(&self, collection: &str, fragment_ids: &[String]) -> Result<(), Error>;
+
+  async fn search(&self, query: SearchQuery) -> Result<Vec<SearchHit>, Error>;
+
+  /// All points whose payload matches the filter
+  async fn scroll(&self, collection: &str, filter: Option<PayloadFilter>) -> Result<Vec<StoredPoint>, Error>;
+
+  /// Persist the changes of an upload, called once it is done. Backends
+  /// that write every change straight away have nothing to do.
+  async fn flush(&self) -> Result<(), Error> { Ok(()) }
+}
+
+/// Connect to the backend selected by `database.backend` (default: qdrant)
+pub async fn connect() -> Result<Box<dyn Database>, Error> {
+  let (backend, url, local) = {
+    let settings = SETTINGS.read().unwrap();
+    let backend = settings.get_str("database.backend").unwrap_or("qdrant".to_string());
+    let local = match backend.as_str() {
+      "local" => Some(LocalStore::open(&settings)?),
+      _ => None,
+    };
+    (backend, settings.get_str("database.url").unwrap_or_default(), local)
+  };
+
+  match backend.as_str() {
+    "qdrant" => Ok(Box::new(QdrantDatabase::connect(&url).await?)),
+    "local" => Ok(Box::new(local.unwrap())),
+    _ => Err(anyhow!("Unknown database backend: {}", backend)),
+  }
+}
+
+// --| Database Functions -------------
+// --|---------------------------------
+/// Upsert the documents, creating the collection sized for the model first
+pub async fn add_documents(database: &dyn Database, mut documents: EmbeddedDocuments) -> Result<(), Error> {
+  documents.collection = collection_name(&documents.collection);
+
+  if database.collection_info(&documents.collection).await?.is_none() {
+    info!("Creating collection: {}", &documents.collection);
+    database.create_collection(&documents.collection, documents.dimensions).await?;
+  }
+
+  database.upsert(documents).await
+}
+
+pub async fn delete_fragments(database: &dyn Database, collection: &str, fragment_ids: &[String]) -> Result<(), Error> {
+  if fragment_ids.is_empty() { return Ok(()); }
+
+  let collection = collection_name(collection);
+  if database.collection_info(&collection).await?.is_none() { return Ok(()); }
+
+  database.delete(&collection, fragment_ids).await
+}
+
+// --| Test Connectivity --------------
+// --|---------------------------------
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionTest {
+  pub collections: Vec<String>,
+  pub collection: Option<CollectionInfo>,
+  pub hits: Vec<SearchHit>,
+}
+
+/// Round trip through a small `test` collection
+pub async fn test_connection(database: &dyn Database) -> Result<ConnectionTest, Error> {
+  let collections = database.list_collections().await?;
+
+  let collection_name = "test";
+  if database.collection_info(collection_name).await?.is_some() {
+    database.drop_collection(collection_name).await?;
+  }
+
+  database.create_collection(collection_name, 10).await?;
+
+  let collection = database.collection_info(collection_name).await?;
+
+  let mut documents = EmbeddedDocuments::new();
+  documents.collection = collection_name.to_string();
+  documents.dimensions = 10;
+  documents.documents.push(EmbeddedDocument {
+    id: "test_0".to_string(),
+    document_id: "test".to_string(),
+    name: "test".to_string(),
+    text: "Bar".to_string(),
+    range: TextRange { start_line: 1, end_line: 1, start_byte: 0, end_byte: 3 },
+    embeddings: vec![12.; 10],
+    metadata: HashMap::new(),
+  });
+
+  database.upsert(documents).await?;
+  database.flush().await?;
+
+  let hits = database.search(SearchQuery {
+    collection: collection_name.to_string(),
+    vector: vec![11.; 10],
+    limit: 10,
+    ..Default::default()
+  }).await?;
+
+  Ok(ConnectionTest { collections, collection, hits })
+}
+
+pub fn print_connection_test(test: &ConnectionTest) {
+  println!("Connected, {} collections: {}", test.collections.len(), test.collections.join(", "));
+
+  if let Some(collection) = &test.collection {
+    println!("Created {} with {} dimensions", collection.name, collection.dimensions);
+  }
+
+  println!("Search returned {} hits", test.hits.len());
+  for hit in &test.hits {
+    println!("  {} ({:.3})", hit.id, hit.score);
+  }
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+/// The payload stored with every point, shared by all backends
+pub fn fragment_payload(document: &EmbeddedDocument, model: &str) -> HashMap<String, Value> {
+  let now: DateTime<Local> = Local::now();
+  let meta = serde_json::to_string(&document.metadata).unwrap().to_string();
+
+  let mut payload = vec![
+    ("id", Value::from(document.id.clone())),
+    ("document_id", document.document_id.clone().into()),
+    ("name", document.name.clone().into()),
+    ("text", document.text.clone().into()),
+    ("start_line", document.range.start_line.into()),
+    ("end_line", document.range.end_line.into()),
+    ("start_byte", document.range.start_byte.into()),
+    ("end_byte", document.range.end_byte.into()),
+    ("created_at", now.to_string().into()),
+    ("model", model.into()),
+    ("metadata", meta.into()),
+  ];
+
+  // --| Points of a git revision can be filtered by commit and reference
+  for key in ["git_commit", "git_ref"] {
+    if let Some(value) = document.metadata.get(key) { payload.push((key, value.clone())); }
+  }
+
+  payload.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
+
+pub fn collection_name(collection: &str) -> String {
+  if collection.is_empty() { "test_collection".to_string() }
+  else { collection.to_string() }
 }
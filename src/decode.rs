@@ -0,0 +1,122 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+
+// Bytes checked for NUL bytes when looking for binary content, as git does
+const BINARY_CHECK_SIZE: usize = 8000;
+
+// Share of NUL bytes in every other position for text to be UTF-16 without a BOM
+const UTF16_NUL_RATIO: f64 = 0.3;
+
+// --| Decoded ------------------------
+// --|---------------------------------
+/// Decoded text is UTF-8 without the byte order mark, `bom` is the length
+/// of the mark that was stripped
+pub enum Decoded {
+  Text { text: String, encoding: &'static str, bom: usize },
+  Binary,
+}
+
+// --| Decode -------------------------
+// --|---------------------------------
+/// Decode file content to text. A byte order mark decides the encoding,
+/// then NUL bytes mark binary content unless they look like UTF-16. Content
+/// that is not valid UTF-8 is transcoded from the detected legacy encoding.
+pub fn decode(bytes: &[u8]) -> Decoded {
+  if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+    return Decoded::Text { text: text.into_owned(), encoding: encoding.name(), bom: bom_length };
+  }
+
+  let sample = &bytes[..bytes.len().min(BINARY_CHECK_SIZE)];
+  if sample.contains(&0) {
+    let encoding = match utf16_encoding(sample) {
+      Some(encoding) => encoding,
+      None => return Decoded::Binary,
+    };
+
+    return match encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+      Some(text) => Decoded::Text { text: text.into_owned(), encoding: encoding.name(), bom: 0 },
+      None => Decoded::Binary,
+    };
+  }
+
+  if let Ok(text) = std::str::from_utf8(bytes) {
+    return Decoded::Text { text: text.to_string(), encoding: "UTF-8", bom: 0 };
+  }
+
+  let mut detector = EncodingDetector::new();
+  detector.feed(bytes, true);
+  let encoding = detector.guess(None, true);
+  let (text, _, _) = encoding.decode(bytes);
+
+  Decoded::Text { text: text.into_owned(), encoding: encoding.name(), bom: 0 }
+}
+
+/// Mostly ASCII text in UTF-16 has a NUL byte in every other position
+fn utf16_encoding(sample: &[u8]) -> Option<&'static Encoding> {
+  let pairs = sample.len() / 2;
+  if pairs == 0 { return None; }
+
+  let even = sample.iter().step_by(2).filter(|b| **b == 0).count();
+  let odd = sample.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+  let ratio = |count: usize| count as f64 / pairs as f64;
+
+  if even == 0 && ratio(odd) >= UTF16_NUL_RATIO { return Some(UTF_16LE); }
+  if odd == 0 && ratio(even) >= UTF16_NUL_RATIO { return Some(UTF_16BE); }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn text(bytes: &[u8]) -> (String, &'static str, usize) {
+    match decode(bytes) {
+      Decoded::Text { text, encoding, bom } => (text, encoding, bom),
+      Decoded::Binary => panic!("decoded as binary"),
+    }
+  }
+
+  fn utf16(text: &str, big_endian: bool) -> Vec<u8> {
+    text.encode_utf16()
+      .flat_map(|unit| if big_endian { unit.to_be_bytes() } else { unit.to_le_bytes() })
+      .collect()
+  }
+
+  #[test]
+  fn utf8_bom_is_stripped() {
+    assert_eq!(text(b"\xEF\xBB\xBFfn main() {}"), ("fn main() {}".to_string(), "UTF-8", 3));
+  }
+
+  #[test]
+  fn utf16_with_bom() {
+    let mut little = vec![0xFF, 0xFE];
+    little.extend(utf16("héllo wörld", false));
+    assert_eq!(text(&little), ("héllo wörld".to_string(), "UTF-16LE", 2));
+
+    let mut big = vec![0xFE, 0xFF];
+    big.extend(utf16("héllo wörld", true));
+    assert_eq!(text(&big), ("héllo wörld".to_string(), "UTF-16BE", 2));
+  }
+
+  #[test]
+  fn utf16_without_bom() {
+    assert_eq!(text(&utf16("plain ascii text", false)), ("plain ascii text".to_string(), "UTF-16LE", 0));
+    assert_eq!(text(&utf16("plain ascii text", true)), ("plain ascii text".to_string(), "UTF-16BE", 0));
+  }
+
+  #[test]
+  fn latin1_is_transcoded() {
+    let bytes = b"Caf\xE9 cr\xE8me br\xFBl\xE9e, une sp\xE9cialit\xE9 fran\xE7aise tr\xE8s appr\xE9ci\xE9e";
+    let (decoded, encoding, bom) = text(bytes);
+    assert_eq!(decoded, "Café crème brûlée, une spécialité française très appréciée");
+    assert_eq!(encoding, "windows-1252");
+    assert_eq!(bom, 0);
+  }
+
+  #[test]
+  fn nul_bytes_are_binary() {
+    assert!(matches!(decode(b"\x7FELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x03\x00>\x00"), Decoded::Binary));
+    assert!(matches!(decode(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D]), Decoded::Binary));
+  }
+}
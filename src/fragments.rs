@@ -1,11 +1,51 @@
 use simplelog::*;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 use tiktoken_rs::p50k_base;
+use tokenizers::Tokenizer;
+use tokenizers::models::ModelWrapper;
+use tokenizers::models::wordpiece::WordPiece;
+use tokenizers::normalizers::{NormalizerWrapper, bert::BertNormalizer};
+use tokenizers::pre_tokenizers::{PreTokenizerWrapper, bert::BertPreTokenizer};
 
-// Any higher can be truncated by the encoding model
+use crate::model::{SPECIAL_TOKENS, configured_max_sequence_length};
+
+// Without the model tokenizer, words are counted instead. Any higher can be
+// truncated by the encoding model
 const MAX_TOKENS: usize = 200;
 
+lazy_static! {
+  // --| Loaded once per source, None if it could not be loaded
+  static ref TOKENIZERS: Mutex<HashMap<String, Option<Arc<Tokenizer>>>> = Mutex::new(HashMap::new());
+}
+
+// --| Fragment -----------------------
+// --|---------------------------------
+/// A range of a document. `header` is prepended to the text when it is
+/// embedded, but not stored with it.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+  pub text: String,
+  pub header: String,
+  pub start_byte: usize,
+  pub end_byte: usize,
+  pub tokens: usize,
+  pub overlap: usize,
+  pub truncated: bool,
+  pub metadata: HashMap<String, Value>,
+}
+
+impl Fragment {
+  /// The text given to the model
+  pub fn embedding_text(&self) -> String {
+    format!("{}{}", self.header, self.text)
+  }
+}
+
 pub fn tokenizer(text: &str) -> Vec<&str>  {
-    text.split(' ').collect::<Vec<&str>>() 
+    text.split(' ').collect::<Vec<&str>>()
 }
 
 pub fn _tokenize(text: &str) -> Vec<usize> {
@@ -20,41 +60,376 @@ pub fn _decode(tokens: Vec<usize>) -> String {
   text.unwrap()
 }
 
-pub fn create_fragments_from_text(document: String, settings: &config::Config) -> Vec<String>  {
-  let tokens = tokenizer(&document);
+// --| Fragment Size ----------------
+// --|---------------------------------
+/// The requested size of fragments, before it is clamped to the model limit.
+/// `max_tokens` of 0 uses the model limit, `overlap` is a token count or a
+/// percentage of `max_tokens` ("15%").
+#[derive(Debug, Clone, Default)]
+pub struct FragmentSize {
+  pub max_tokens: i64,
+  pub overlap: String,
+}
+
+impl FragmentSize {
+  /// `database.max_tokens` and `database.overlap`
+  pub fn from_settings(settings: &config::Config) -> Self {
+    Self {
+      max_tokens: settings.get_int("database.max_tokens").unwrap_or(0),
+      overlap: settings.get_str("database.overlap").unwrap_or_default(),
+    }
+  }
+}
+
+// --| Tokens -------------------------
+// --|---------------------------------
+/// Token offsets of a document, measured with the tokenizer of the embedding
+/// model, with the fragment limits that apply to it. Without the model
+/// tokenizer, words are counted instead.
+pub struct Tokens {
+  pub offsets: Vec<(usize, usize)>,
+  pub max_tokens: usize,
+  pub overlap: usize,
+  limit: usize,
+  tokenizer: Option<Arc<Tokenizer>>,
+}
+
+impl Tokens {
+  pub fn new(document: &str, size: &FragmentSize, settings: &config::Config) -> Self {
+    let limit = model_limit(settings);
+
+    if let Some(tokenizer) = load_tokenizer(settings) {
+      match tokenizer.encode(document, false) {
+        Ok(encoding) => {
+          let max_tokens = max_tokens(size.max_tokens, limit);
+          return Self {
+            offsets: encoding.get_offsets().to_vec(),
+            overlap: overlap_tokens(&size.overlap, max_tokens),
+            max_tokens,
+            limit,
+            tokenizer: Some(tokenizer),
+          };
+        },
+        Err(err) => warn!("Could not tokenize document, counting words instead: {}", err),
+      }
+    }
+
+    // --| A lower model limit still applies to the words
+    let limit = limit.min(MAX_TOKENS);
+    let max_tokens = max_tokens(size.max_tokens, limit);
+    let mut offsets: Vec<(usize, usize)> = Vec::new();
+    let mut position = 0;
+    for token in tokenizer(document) {
+      if !token.is_empty() { offsets.push((position, position + token.len())); }
+      position += token.len() + 1;
+    }
+
+    Self { offsets, max_tokens, overlap: overlap_tokens(&size.overlap, max_tokens), limit, tokenizer: None }
+  }
+
+  /// Use a smaller fragment size, e.g. to make room for a header. A
+  /// percentage overlap applies to the new size.
+  pub fn resize(&mut self, max_tokens: usize, size: &FragmentSize) {
+    self.max_tokens = max_tokens.max(1);
+    self.overlap = overlap_tokens(&size.overlap, self.max_tokens);
+  }
+
+  /// Tokens of a text on its own, e.g. a header
+  pub fn count_text(&self, text: &str) -> usize {
+    if text.is_empty() { return 0; }
+
+    match &self.tokenizer {
+      Some(tokenizer) => tokenizer.encode(text, false).map(|e| e.get_ids().len()).unwrap_or_else(|_| count_words(text)),
+      None => count_words(text),
+    }
+  }
+
+  /// Tokens starting between two byte positions
+  pub fn count(&self, start: usize, end: usize) -> usize {
+    self.range(start, end).len()
+  }
 
-  let mut max_tokens = 0;
-  if let Ok(max) = settings.get_int("database.max_tokens") {
-    max_tokens = max as usize;
+  /// A single fragment for `document[start..end]`
+  pub fn fragment(&self, document: &str, start: usize, end: usize) -> Fragment {
+    Fragment {
+      text: document[start..end].to_string(),
+      header: String::new(),
+      start_byte: start,
+      end_byte: end,
+      tokens: self.count(start, end),
+      overlap: 0,
+      truncated: false,
+      metadata: HashMap::new(),
+    }
   }
-  
-  if max_tokens == 0 || max_tokens > MAX_TOKENS { // Prevent truncation
-    max_tokens = MAX_TOKENS as usize; 
+
+  /// Overlapping windows of `max_tokens` over `document[start..end]`
+  pub fn windows(&self, document: &str, start: usize, end: usize) -> Vec<Fragment> {
+    create_windows(document, self.range(start, end), (start, end), self.max_tokens, self.overlap)
   }
 
-  let mut fragments: Vec<String> = Vec::new();
-  let mut fragment = Vec::new();
-  let mut fragment_length = 0;
-  let token_total = tokens.clone().len();
+  /// Consecutive blocks share a fragment while they fit into `max_tokens`,
+  /// blocks that are larger on their own are split into windows
+  pub fn merge(&self, document: &str, blocks: &[(usize, usize)]) -> Vec<Fragment> {
+    let mut fragments: Vec<Fragment> = Vec::new();
+    let (mut start, mut end) = (0, 0);
+
+    for &(block_start, block_end) in blocks {
+      if self.count(block_start, block_end) > self.max_tokens {
+        if end > start { fragments.push(self.fragment(document, start, end)); }
+        fragments.extend(self.windows(document, block_start, block_end));
+        start = block_end;
+        end = block_end;
+        continue;
+      }
 
-  info!("Token total: {}", token_total);
-  info!("Max tokens: {}", max_tokens);
+      if end > start && self.count(start, block_end) > self.max_tokens {
+        fragments.push(self.fragment(document, start, end));
+        start = block_start;
+      }
+      if end <= start { start = block_start; }
+      end = block_end;
+    }
+
+    if end > start { fragments.push(self.fragment(document, start, end)); }
+    fragments
+  }
 
+  /// Re-tokenizing the text on its own can differ slightly from the
+  /// window, so the final size of each fragment, with its header, is
+  /// measured and checked against the model limit. Without the model
+  /// tokenizer, the words of the fragment and its header are checked
+  /// against the word limit.
+  pub fn measure(&self, fragments: &mut Vec<Fragment>) {
+    fragments.retain(|fragment| !fragment.text.trim().is_empty());
 
-  for token in tokens {
-    fragment.push(token.clone());
-    fragment_length += 1;
+    for (i, fragment) in fragments.iter_mut().enumerate() {
+      fragment.tokens = self.count_text(&fragment.embedding_text());
+      fragment.truncated = fragment.tokens > self.limit;
+      if !fragment.truncated { continue; }
 
-    if fragment_length == max_tokens  {
-      fragments.push(fragment.join(" "));
-      fragment = Vec::new();
-      fragment_length = 0;
+      match self.tokenizer {
+        Some(_) => warn!("Fragment {} has {} tokens and will be truncated to {} by the model", i, fragment.tokens, self.limit),
+        None => warn!("Fragment {} has {} words, more than {} can be truncated by the model", i, fragment.tokens, self.limit),
+      }
     }
   }
 
-  if fragment_length > 0 { 
-    fragments.push(fragment.join(" "));
+  fn range(&self, start: usize, end: usize) -> &[(usize, usize)] {
+    let first = self.offsets.partition_point(|offset| offset.0 < start);
+    let last = self.offsets.partition_point(|offset| offset.0 < end);
+    &self.offsets[first..last.max(first)]
+  }
+}
+
+/// Words counted in place of tokens without the model tokenizer
+fn count_words(text: &str) -> usize {
+  tokenizer(text).iter().filter(|token| !token.is_empty()).count()
+}
+
+/// Windows of `max_tokens` tokens, each starting `max_tokens - overlap`
+/// tokens after the previous one. The text of a window runs up to the
+/// start of the token after it, so no text is lost between windows.
+fn create_windows(document: &str, offsets: &[(usize, usize)], range: (usize, usize), max_tokens: usize, overlap: usize) -> Vec<Fragment> {
+  let step = max_tokens - overlap;
+  let mut fragments: Vec<Fragment> = Vec::new();
+  let mut start = 0;
+  let mut previous_end: usize = 0;
+
+  while start < offsets.len() {
+    let end = (start + max_tokens).min(offsets.len());
+    let start_byte = if start == 0 { range.0 } else { offsets[start].0 };
+    let end_byte = if end < offsets.len() { offsets[end].0 } else { range.1 };
+
+    fragments.push(Fragment {
+      text: document[start_byte..end_byte].to_string(),
+      header: String::new(),
+      start_byte,
+      end_byte,
+      tokens: end - start,
+      overlap: previous_end.saturating_sub(start),
+      truncated: false,
+      metadata: HashMap::new(),
+    });
+
+    if end == offsets.len() { break; }
+    previous_end = end;
+    start += step;
   }
 
   fragments
 }
+
+/// The overlap in tokens, either a count ("32") or a percentage of
+/// `max_tokens` ("15%"). Always leaves at least one new token per fragment.
+fn overlap_tokens(overlap: &str, max_tokens: usize) -> usize {
+  let value = overlap.trim();
+  let parsed = match value.strip_suffix('%') {
+    Some(percent) => percent.trim().parse::<f64>().map(|p| (max_tokens as f64 * p / 100.).round() as usize),
+    None if value.is_empty() => Ok(0),
+    None => value.parse::<f64>().map(|t| t as usize),
+  };
+
+  let overlap = parsed.unwrap_or_else(|_| {
+    warn!("Invalid overlap: {}, using no overlap", value);
+    0
+  });
+
+  overlap.min(max_tokens.saturating_sub(1))
+}
+
+/// Word pieces of a fragment the model embeds without truncating it
+fn model_limit(settings: &config::Config) -> usize {
+  configured_max_sequence_length(settings).saturating_sub(SPECIAL_TOKENS).max(1)
+}
+
+/// The requested max tokens, clamped to `limit`
+fn max_tokens(requested: i64, limit: usize) -> usize {
+  let mut max_tokens = requested.max(0) as usize;
+
+  if max_tokens > limit {
+    debug!("max_tokens {} is above the model limit, using {}", max_tokens, limit);
+  }
+
+  if max_tokens == 0 || max_tokens > limit { // Prevent truncation
+    max_tokens = limit;
+  }
+
+  max_tokens
+}
+
+// --| Tokenizer ----------------------
+// --|---------------------------------
+/// The tokenizer of the configured model. `model.tokenizer` can point to a
+/// tokenizer.json, otherwise the local model directory is used, or it is
+/// downloaded for the remote models.
+pub fn load_tokenizer(settings: &config::Config) -> Option<Arc<Tokenizer>> {
+  let source = tokenizer_source(settings);
+  let mut tokenizers = TOKENIZERS.lock().unwrap();
+
+  if let Some(tokenizer) = tokenizers.get(&source) {
+    return tokenizer.clone();
+  }
+
+  // --| Downloads use a blocking client, which must not run on a runtime worker
+  let loaded = std::thread::scope(|scope| {
+    scope.spawn(|| create_tokenizer(&source)).join()
+      .unwrap_or_else(|_| Err("tokenizer thread panicked".into()))
+  });
+
+  let tokenizer = match loaded {
+    Ok(tokenizer) => {
+      debug!("Loaded tokenizer from {}", &source);
+      Some(Arc::new(tokenizer))
+    },
+    Err(err) => {
+      warn!("Could not load tokenizer from {}, fragment sizes are approximate: {}", &source, err);
+      None
+    }
+  };
+
+  tokenizers.insert(source, tokenizer.clone());
+  tokenizer
+}
+
+fn tokenizer_source(settings: &config::Config) -> String {
+  if let Ok(path) = settings.get_str("model.tokenizer") {
+    if !path.is_empty() { return path; }
+  }
+
+  let location = settings.get_str("model.location").unwrap_or("L12".to_string());
+  if settings.get_bool("model.local").unwrap_or(false) {
+    let directory = PathBuf::from(&location);
+    let tokenizer = directory.join("tokenizer.json");
+    if tokenizer.exists() { return tokenizer.display().to_string(); }
+    return directory.join("vocab.txt").display().to_string();
+  }
+
+  format!("sentence-transformers/all-MiniLM-{}-v2", location)
+}
+
+fn create_tokenizer(source: &str) -> tokenizers::Result<Tokenizer> {
+  let mut tokenizer = if source.ends_with("vocab.txt") {
+    // --| Bert models without a tokenizer.json only ship the word piece vocabulary
+    let wordpiece = WordPiece::from_file(source).unk_token("[UNK]".to_string()).build()?;
+    let mut tokenizer = Tokenizer::new(ModelWrapper::WordPiece(wordpiece));
+    tokenizer.with_normalizer(NormalizerWrapper::from(BertNormalizer::default()));
+    tokenizer.with_pre_tokenizer(PreTokenizerWrapper::from(BertPreTokenizer));
+    tokenizer
+  } else if source.ends_with(".json") {
+    Tokenizer::from_file(source)?
+  } else {
+    Tokenizer::from_pretrained(source, None)?
+  };
+
+  // --| Whole documents are tokenized, so the model's own limits are removed
+  tokenizer.with_truncation(None);
+  tokenizer.with_padding(None);
+  Ok(tokenizer)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TEXT: &str = "a b c d e f g";
+
+  fn offsets(text: &str) -> Vec<(usize, usize)> {
+    let mut offsets = vec![];
+    let mut position = 0;
+    for token in tokenizer(text) {
+      offsets.push((position, position + token.len()));
+      position += token.len() + 1;
+    }
+    offsets
+  }
+
+  fn texts(fragments: &[Fragment]) -> Vec<&str> {
+    fragments.iter().map(|f| f.text.as_str()).collect()
+  }
+
+  #[test]
+  fn windows_without_overlap_are_contiguous() {
+    let fragments = create_windows(TEXT, &offsets(TEXT), (0, TEXT.len()), 3, 0);
+
+    assert_eq!(texts(&fragments), vec!["a b c ", "d e f ", "g"]);
+    assert_eq!(fragments.iter().map(|f| (f.start_byte, f.end_byte, f.tokens, f.overlap)).collect::<Vec<_>>(),
+      vec![(0, 6, 3, 0), (6, 12, 3, 0), (12, 13, 1, 0)]);
+  }
+
+  #[test]
+  fn windows_share_overlap_tokens() {
+    let fragments = create_windows(TEXT, &offsets(TEXT), (0, TEXT.len()), 3, 1);
+
+    assert_eq!(texts(&fragments), vec!["a b c ", "c d e ", "e f g"]);
+    assert_eq!(fragments.iter().map(|f| f.overlap).collect::<Vec<_>>(), vec![0, 1, 1]);
+  }
+
+  #[test]
+  fn short_text_is_a_single_window() {
+    let fragments = create_windows("a b", &offsets("a b"), (0, 3), 5, 2);
+
+    assert_eq!(texts(&fragments), vec!["a b"]);
+    assert_eq!((fragments[0].tokens, fragments[0].overlap), (2, 0));
+  }
+
+  #[test]
+  fn overlap_is_a_count_or_percentage() {
+    assert_eq!(overlap_tokens("", 100), 0);
+    assert_eq!(overlap_tokens("0", 100), 0);
+    assert_eq!(overlap_tokens("32", 100), 32);
+    assert_eq!(overlap_tokens("15%", 100), 15);
+    assert_eq!(overlap_tokens("15%", 10), 2);
+    assert_eq!(overlap_tokens("some", 100), 0);
+  }
+
+  #[test]
+  fn overlap_leaves_one_new_token_per_window() {
+    assert_eq!(overlap_tokens("100", 100), 99);
+    assert_eq!(overlap_tokens("150%", 10), 9);
+
+    let overlap = overlap_tokens("5", 3);
+    let fragments = create_windows(TEXT, &offsets(TEXT), (0, TEXT.len()), 3, overlap);
+    assert_eq!(texts(&fragments), vec!["a b c ", "b c d ", "c d e ", "d e f ", "e f g"]);
+  }
+}
@@ -0,0 +1,180 @@
+use simplelog::*;
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use git2::{Delta, DiffFindOptions, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+
+use crate::manifest::relative_path;
+
+// Git file mode of symbolic links, which are not followed in a revision
+const SYMLINK_MODE: i32 = 0o120000;
+
+// --| Git Selection ------------------
+// --|---------------------------------
+/// Limits a run to the files a git diff touched: the changes between a
+/// revision and the working tree (`--since <rev>`), or the staged changes
+/// (`--staged`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum GitSelection {
+  Since(String),
+  Staged,
+}
+
+impl GitSelection {
+  /// `git.since` or `git.staged`, none if the whole project is indexed
+  pub fn from_settings(settings: &config::Config) -> Option<Self> {
+    if let Ok(rev) = settings.get_str("git.since") {
+      if !rev.is_empty() { return Some(GitSelection::Since(rev)); }
+    }
+
+    match settings.get_bool("git.staged").unwrap_or(false) {
+      true => Some(GitSelection::Staged),
+      false => None,
+    }
+  }
+}
+
+// --| Git Changes --------------------
+// --|---------------------------------
+/// The files of the project a diff added or modified, and the ones it
+/// removed, relative to the project root. A renamed file is removed under
+/// its old path and changed under its new one.
+#[derive(Debug, Clone, Default)]
+pub struct GitChanges {
+  pub changed: Vec<PathBuf>,
+  pub removed: Vec<String>,
+}
+
+/// The changes of the repository containing the project root. Only files
+/// within the project root are listed.
+pub fn changes(root: &Path, selection: &GitSelection) -> Result<GitChanges> {
+  let repository = Repository::discover(root)?;
+  let workdir = repository.workdir()
+    .ok_or(anyhow!("{} is in a bare repository", root.display()))?
+    .to_path_buf();
+
+  let mut diff = match selection {
+    GitSelection::Since(rev) => {
+      let tree = repository.revparse_single(rev)?.peel_to_tree()?;
+      repository.diff_tree_to_workdir_with_index(Some(&tree), None)?
+    },
+    GitSelection::Staged => {
+      // --| Everything in the index is staged before the first commit
+      let head = repository.head().ok().and_then(|head| head.peel_to_tree().ok());
+      repository.diff_tree_to_index(head.as_ref(), None, None)?
+    },
+  };
+  diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
+
+  let mut changes = GitChanges::default();
+  let within = |path: Option<&Path>| path.map(|path| workdir.join(path)).filter(|path| path.starts_with(root));
+
+  for delta in diff.deltas() {
+    let old = within(delta.old_file().path());
+    let new = within(delta.new_file().path());
+
+    match delta.status() {
+      Delta::Added | Delta::Modified | Delta::Copied | Delta::Typechange => changes.changed.extend(new),
+      Delta::Deleted => changes.removed.extend(old.map(|path| relative_path(&path, root))),
+      Delta::Renamed => {
+        changes.removed.extend(old.map(|path| relative_path(&path, root)));
+        changes.changed.extend(new);
+      },
+      status => debug!("Skipping {:?}: {:?}", delta.new_file().path(), status),
+    }
+  }
+
+  // --| A file the diff changed can be gone from disk since
+  let (existing, gone): (Vec<PathBuf>, Vec<PathBuf>) = changes.changed.into_iter().partition(|path| path.is_file());
+  changes.changed = existing;
+  changes.removed.extend(gone.iter().map(|path| relative_path(path, root)));
+
+  info!("Git changes: {} changed, {} removed files", changes.changed.len(), changes.removed.len());
+  Ok(changes)
+}
+
+// --| Revision -----------------------
+// --|---------------------------------
+/// A commit of the repository containing the project root, read straight
+/// from the object database (`--git-rev <rev>`)
+pub struct Revision {
+  repository: Repository,
+  workdir: PathBuf,
+  tree: Oid,
+  pub commit: String,
+  pub reference: String,
+}
+
+/// A file of a revision, by its path relative to the project root
+#[derive(Debug, Clone)]
+pub struct RevisionFile {
+  pub relative: PathBuf,
+  pub size: u64,
+  pub blob: Oid,
+}
+
+impl Revision {
+  /// The commit a revision points to. The reference name is the one the
+  /// revision resolves through, e.g. `refs/tags/v1.0`, or the revision as
+  /// given when it is a commit SHA.
+  pub fn open(root: &Path, rev: &str) -> Result<Self> {
+    // --| The files of a bare repository are placed in the project root
+    let repository = Repository::discover(root)?;
+    let workdir = repository.workdir().unwrap_or(root).to_path_buf();
+
+    let (tree, commit, reference) = {
+      let (object, reference) = repository.revparse_ext(rev)?;
+      let reference = reference.and_then(|r| r.name().map(|name| name.to_string())).unwrap_or(rev.to_string());
+      let commit = object.peel_to_commit()?;
+      (commit.tree_id(), commit.id().to_string(), reference)
+    };
+
+    info!("Reading {} at {}", reference, commit);
+    Ok(Self { repository, workdir, tree, commit, reference })
+  }
+
+  /// The files of the revision within the project root, sorted by path
+  pub fn files(&self, root: &Path) -> Result<Vec<RevisionFile>> {
+    let tree = self.repository.find_tree(self.tree)?;
+    let odb = self.repository.odb()?;
+    let mut files: Vec<RevisionFile> = vec![];
+
+    tree.walk(TreeWalkMode::PreOrder, |directory, entry| {
+      if entry.kind() != Some(ObjectType::Blob) || entry.filemode() == SYMLINK_MODE {
+        return TreeWalkResult::Ok;
+      }
+
+      let name = match entry.name() {
+        Some(name) => name,
+        None => return TreeWalkResult::Ok,
+      };
+
+      let path = self.workdir.join(directory).join(name);
+      if let Ok(relative) = path.strip_prefix(root) {
+        let size = odb.read_header(entry.id()).map(|(size, _)| size as u64).unwrap_or(0);
+        files.push(RevisionFile { relative: relative.to_path_buf(), size, blob: entry.id() });
+      }
+      TreeWalkResult::Ok
+    })?;
+
+    files.sort_by(|a, b| a.relative.cmp(&b.relative));
+    Ok(files)
+  }
+
+  pub fn read(&self, blob: Oid) -> Result<Vec<u8>> {
+    Ok(self.repository.find_blob(blob)?.content().to_vec())
+  }
+
+  /// An ignore file of the revision, by the path it would have on disk
+  pub fn ignore_file(&self, file: &Path) -> Option<Gitignore> {
+    let path = file.strip_prefix(&self.workdir).ok()?;
+    let entry = self.repository.find_tree(self.tree).ok()?.get_path(path).ok()?;
+    let blob = self.repository.find_blob(entry.id()).ok()?;
+
+    let mut builder = GitignoreBuilder::new(file.parent()?);
+    for line in String::from_utf8_lossy(blob.content()).lines() {
+      let _ = builder.add_line(Some(file.to_path_buf()), line);
+    }
+    builder.build().ok()
+  }
+}
@@ -1,15 +1,17 @@
 use simplelog::*;
 use std::fs::File;
+use serde::Serialize;
 use serde_json::Value;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
-use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
 
 use crate::SETTINGS;
-use crate::fragments::create_fragments_from_text;
-use crate::data_types::{Documents, Document, MetaDataStore};
-use crate::walker::{FileWalker, DirEntry};
+use crate::manifest::{Manifest, content_hash, relative_path};
+use crate::chunker::create_fragments;
+use crate::decode::{Decoded, decode};
+use crate::data_types::{Documents, Document, FileError, MetaDataStore};
+use crate::git::{self, GitSelection, Revision, RevisionFile};
+use crate::walker::{FileWalker, Candidate, Verdict};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -17,13 +19,6 @@ pub enum Error {
   Io(#[from] std::io::Error),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct IndexConfig {
-  extensions: Vec<String>,
-  directories: Vec<String>,
-  ignored: Vec<String>,
-}
-
 impl serde::Serialize for Error {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where S: serde::Serializer, { serializer.serialize_str(self.to_string().as_ref()) }
@@ -33,53 +28,210 @@ type Index = BTreeMap<String, String>;
 
 // --| Index Files ------------------------------
 // --|-------------------------------------------
-pub fn build_index() -> Documents {
+/// Files whose content hash matches the manifest are skipped and only
+/// listed in `Documents::unchanged`. With `--since` or `--staged` only the
+/// files the git diff touched are indexed, the files it removed are listed
+/// in `Documents::removed`. With `--git-rev` the files of the revision are
+/// indexed instead of the ones on disk. Invalid matcher rules, git errors
+/// and an unreadable revision are an error, as an empty index would have
+/// every uploaded file reported missing.
+pub fn build_index(manifest: Option<&Manifest>) -> Result<Documents, anyhow::Error> {
   let settings = SETTINGS.write().unwrap();
 
   let project_path = PathBuf::from(settings.get_str("indexer.project").unwrap());
   let mut documents: Documents = Documents::new();
 
-  if let Some(collection) = settings.get_str("database.collection").ok() {
+  if let Ok(collection) = settings.get_str("database.collection") {
     documents.collection = collection;
   }
 
   info!("Indexing Files...");
 
-  if let Some(rules) = settings.get_array("matcher.rules").ok() {
-    let rules = rules.iter().map(|rule| rule.to_string()).collect::<Vec<String>>();
-    
-    let mut matcher = FileWalker::new(&project_path); 
-    let files = &matcher.walk_files(&rules);
-    dbg!(&files.iter().map(|x| x.path().to_str().unwrap()).collect::<Vec<&str>>());
+  let rev = settings.get_str("git.rev").unwrap_or_default();
+  if !rev.is_empty() {
+    if !project_path.is_dir() {
+      return Err(anyhow::anyhow!("--git-rev needs a project directory, not a single file"));
+    }
+
+    let root = PathBuf::from(settings.get_str("indexer.root")?);
+    let walker = FileWalker::from_settings(&root, &settings)
+      .map_err(|err| anyhow::anyhow!("Invalid matcher rules: {}", err))?;
+
+    documents = handle_revision(&rev, &walker, &root, &settings, manifest)
+      .map_err(|err| anyhow::anyhow!("Could not read revision {}: {}", rev, err))?;
+  } else if project_path.is_file() {
+    documents = handle_file(&project_path, &settings, manifest);
+  } else if project_path.is_dir() {
+    let walker = FileWalker::from_settings(&project_path, &settings)
+      .map_err(|err| anyhow::anyhow!("Invalid matcher rules: {}", err))?;
+
+    let selection = GitSelection::from_settings(&settings);
+    let (files, removed) = match &selection {
+      None => (walker.walk_files().iter().map(|x| x.path().to_path_buf()).collect::<Vec<PathBuf>>(), vec![]),
+      Some(selection) => git_files(selection, &settings)
+        .map_err(|err| anyhow::anyhow!("Could not read the git changes: {}", err))?,
+    };
+
+    debug!("Files: {:?}", files.iter().map(|x| x.display().to_string()).collect::<Vec<String>>());
+    documents = handle_files(&files, &settings, manifest);
+    documents.partial = selection.is_some();
+    documents.removed = removed;
+  }
+
+  log_summary(&documents);
+  Ok(documents)
+}
+
+// --| Index Paths ------------------------------
+// --|-------------------------------------------
+/// Index the given files only, such as the files that changed in watch
+/// mode. The files are expected to be selected by the walker already.
+pub fn index_paths(paths: &[PathBuf], manifest: Option<&Manifest>) -> Documents {
+  let settings = SETTINGS.read().unwrap();
+  let documents = handle_files(paths, &settings, manifest);
+
+  log_summary(&documents);
+  documents
+}
 
-    documents = handle_matcher(files, &settings);
+fn log_summary(documents: &Documents) {
+  info!("Total documents: {}", documents.documents.len());
 
+  let truncated = documents.documents.iter()
+    .flat_map(|d| d.fragments.iter())
+    .filter(|f| f.metadata.contains_key("truncated"))
+    .count();
+  if truncated > 0 {
+    warn!("{} fragments exceed the model's max sequence length and will be truncated", truncated);
+  }
+  if !documents.unchanged.is_empty() {
+    info!("Unchanged documents: {}", documents.unchanged.len());
   }
+  if !documents.skipped.is_empty() {
+    info!("Skipped {} binary files", documents.skipped.len());
+  }
+  if !documents.errors.is_empty() {
+    warn!("{} files could not be indexed", documents.errors.len());
+    for error in &documents.errors { warn!("{}: {}", error.path, error.error); }
+  }
+}
 
-  if Path::new(&project_path).is_dir() {
-   documents = handle_directory(&project_path, &settings)
-  } else if Path::new(&project_path).is_file() {
-   documents = handle_file(&project_path, &settings)
+// --| Explain ----------------------------------
+// --|-------------------------------------------
+/// A candidate path with its verdict, and the fragments an included file
+/// would be split into
+#[derive(Debug, Clone, Serialize)]
+pub struct Explanation {
+  pub path: String,
+  pub directory: bool,
+  pub included: bool,
+  pub verdict: String,
+  pub fragments: Option<usize>,
+}
+
+/// Every path the index would consider, without embedding anything. With
+/// `--git-rev` the files of the revision are explained instead.
+pub fn explain() -> Result<Vec<Explanation>, anyhow::Error> {
+  let settings = SETTINGS.read().unwrap();
+  let project_path = PathBuf::from(settings.get_str("indexer.project")?);
+  let root = PathBuf::from(settings.get_str("indexer.root").unwrap_or_default());
+
+  let rev = settings.get_str("git.rev").unwrap_or_default();
+  if !rev.is_empty() {
+    if !project_path.is_dir() {
+      return Err(anyhow::anyhow!("--git-rev needs a project directory, not a single file"));
+    }
+    return explain_revision(&rev, &root, &settings);
   }
 
-  info!("Total documents: {}", documents.documents.len());
-  documents
+  let candidates = match project_path.is_file() {
+    true => vec![Candidate { path: project_path.clone(), directory: false, verdict: Verdict::Included }],
+    false => FileWalker::from_settings(&project_path, &settings)?.explain(),
+  };
+
+  let explanations = candidates.into_iter().map(|candidate| {
+    let relative = relative_path(&candidate.path, &root);
+    let included = candidate.verdict.is_included();
+
+    let fragments = match included {
+      true => match std::fs::read(&candidate.path).map(|bytes| decode(&bytes)) {
+        Ok(Decoded::Text { text, .. }) => Some(create_fragments(&text, &relative, &settings).len()),
+        _ => None,
+      },
+      false => None,
+    };
+
+    Explanation {
+      path: if candidate.directory { format!("{}/", relative) } else { relative },
+      directory: candidate.directory,
+      included,
+      verdict: candidate.verdict.to_string(),
+      fragments,
+    }
+  }).collect();
+
+  Ok(explanations)
+}
+
+/// The files of a git revision with their verdict, directories are not
+/// listed as the revision is not walked
+fn explain_revision(rev: &str, root: &Path, settings: &config::Config) -> Result<Vec<Explanation>, anyhow::Error> {
+  let walker = FileWalker::from_settings(root, settings)?;
+  let revision = Revision::open(root, rev)?;
+
+  let explanations = revision.files(root)?.into_iter().map(|file| {
+    let verdict = revision_verdict(&walker, &revision, &file);
+    let relative = relative_path(&root.join(&file.relative), root);
+
+    let fragments = match verdict.is_included() {
+      true => match revision.read(file.blob).map(|bytes| decode(&bytes)) {
+        Ok(Decoded::Text { text, .. }) => Some(create_fragments(&text, &relative, settings).len()),
+        _ => None,
+      },
+      false => None,
+    };
+
+    Explanation { path: relative, directory: false, included: verdict.is_included(), verdict: verdict.to_string(), fragments }
+  }).collect();
+
+  Ok(explanations)
 }
 
-// --| Handle Matcher ---------------------------
+pub fn print_explanations(explanations: &[Explanation]) {
+  for explanation in explanations {
+    let fragments = explanation.fragments.map(|f| format!(" ({} fragments)", f)).unwrap_or_default();
+    println!("{}{}: {}", explanation.path, fragments, explanation.verdict);
+  }
+
+  let included = explanations.iter().filter(|e| e.included).count();
+  println!("{} of {} paths included", included, explanations.len());
+}
+
+// --| Git Files --------------------------------
+// --|-------------------------------------------
+/// The files of a git diff the walker selects, and the files it removed.
+/// The diff lists absolute paths, so the walker works on the absolute
+/// project root rather than the path given on the command line.
+fn git_files(selection: &GitSelection, settings: &config::Config) -> Result<(Vec<PathBuf>, Vec<String>), anyhow::Error> {
+  let root = PathBuf::from(settings.get_str("indexer.root")?);
+  let walker = FileWalker::from_settings(&root, settings)?;
+  let changes = git::changes(&root, selection)?;
+
+  let files = changes.changed.into_iter().filter(|path| walker.file_verdict(path).is_included()).collect();
+  Ok((files, changes.removed))
+}
+
+// --| Handle Files -----------------------------
 // --|-------------------------------------------
-fn handle_matcher(files: &Vec<DirEntry>, settings: &config::Config) -> Documents {
-  let metadata_store: MetaDataStore = MetaDataStore::new();
+fn handle_files(files: &[PathBuf], settings: &config::Config, manifest: Option<&Manifest>) -> Documents {
+  let metadata_store = metadata_store(settings);
   let mut documents = Documents::new();
-  if let Some(collection) = settings.get_str("database.collection").ok() {
+  if let Ok(collection) = settings.get_str("database.collection") {
     documents.collection = collection;
   }
 
   for file in files {
-    if file.path().is_dir() { continue; }
-
-    let document = obtain_data(file.path(), &mut metadata_store.metadata.clone(), &settings);
-    documents.add(document);
+    index_file(&mut documents, file, &mut metadata_store.metadata.clone(), &settings, manifest);
   }
 
   documents
@@ -87,166 +239,205 @@ fn handle_matcher(files: &Vec<DirEntry>, settings: &config::Config) -> Documents
 
 // --| Handle File ------------------------------
 // --|-------------------------------------------
-fn handle_file(project_path: &Path, settings: &config::Config) -> Documents {
-  let metadata_store: MetaDataStore = MetaDataStore::new();
+fn handle_file(project_path: &Path, settings: &config::Config, manifest: Option<&Manifest>) -> Documents {
+  let metadata_store = metadata_store(settings);
   let mut documents = Documents::new();
-  if let Some(collection) = settings.get_str("database.collection").ok() {
+  if let Ok(collection) = settings.get_str("database.collection") {
     documents.collection = collection;
   }
 
-  let document = obtain_data(
+  index_file(
+    &mut documents,
     project_path.clone(), 
     &mut metadata_store.metadata.clone(), 
-    &settings
+    &settings,
+    manifest
   );
 
-  documents.add(document);
   documents
 }
 
-// --| Handle Directory -------------------------
+// --| Handle Revision --------------------------
 // --|-------------------------------------------
-fn handle_directory(project_path: &Path, settings: &config::Config) -> Documents { 
-  fn is_hidden(entry: &walkdir::DirEntry) -> bool {
-    entry.file_name().to_str().map(|s| s.starts_with(".")).unwrap_or(false)
-  }
+/// Index the files of a git revision from the object database. They are
+/// selected with the same rules as the files on disk, using the ignore
+/// files of the revision. Every document is tagged with the commit and
+/// the reference name.
+fn handle_revision(rev: &str, walker: &FileWalker, root: &Path, settings: &config::Config, manifest: Option<&Manifest>) -> Result<Documents, anyhow::Error> {
+  let revision = Revision::open(root, rev)?;
 
-  let mut ignored: Vec<String> = Vec::new();
-  let mut extensions: Vec<String> = Vec::new();
-  let mut directories: Vec<String> = Vec::new();
+  let mut metadata_store = metadata_store(settings);
+  metadata_store.metadata.insert("git_commit".to_owned(), Value::String(revision.commit.clone()));
+  metadata_store.metadata.insert("git_ref".to_owned(), Value::String(revision.reference.clone()));
 
-  if let Ok(values) = settings.get::<Vec<String>>("indexer.ignored") { ignored = values; } 
-  if let Ok(values) = settings.get::<Vec<String>>("indexer.extensions") { extensions = values; }
-  if let Ok(values) = settings.get::<Vec<String>>("indexer.directories") { directories = values; }
-
-  if directories.len() == 0 {
-    let cfg_path = project_path.to_str().unwrap();
-    debug!("Using path: {}", &cfg_path);
-    directories.push(cfg_path.to_owned());
-  }
-
-  let config = IndexConfig { ignored, extensions, directories };
-
-  let mut index = Index::new();
   let mut documents = Documents::new();
-  let mut metadata_store: MetaDataStore = MetaDataStore::new();
-
-  if let Ok(store) = &settings.get_str("database.metadata") {
-    metadata_store = MetaDataStore::from_json(store);
+  if let Ok(collection) = settings.get_str("database.collection") {
+    documents.collection = collection;
   }
 
-  for dir in config.directories {
-    if !Path::new(&dir).exists() {
-      warn!("ERROR: {:?} does not exist", dir);
-      continue;
-    }  
-
-    let dir_path = WalkDir::new(&dir);
-    let entries = &mut dir_path.into_iter();
-
-    if let Some(Err(err)) = &entries.next() {
-      warn!("ERROR: {}", err);
+  for file in revision.files(root)? {
+    let verdict = revision_verdict(walker, &revision, &file);
+    if !verdict.is_included() {
+      debug!("{}: {}", file.relative.display(), verdict);
       continue;
     }
 
-    loop {
-      let entry = match entries.next() {
-        None => break,
-        Some(Err(err)) => { warn!("ERROR: {}", err); continue; }
-        Some(Ok(entry)) => entry,
-      };
+    let entry = root.join(&file.relative);
+    match revision.read(file.blob) {
+      Ok(bytes) => index_content(&mut documents, &entry, bytes, &mut metadata_store.metadata.clone(), settings, manifest),
+      Err(err) => {
+        error!("Could not read {}: {}", file.relative.display(), err);
+        documents.errors.push(FileError { path: relative_path(&entry, root), error: err.to_string() });
+      }
+    }
+  }
 
-      // --| Skip ignored directories
-      if config.ignored.len() > 0 {
-        let path_str = entry.path().canonicalize();
-        if path_str.is_err() { continue; }
+  Ok(documents)
+}
 
-        let path = path_str.unwrap().to_str().unwrap().to_owned();
-        let ignore = path.contains(config.ignored.to_owned().join("/").as_str());
+/// The verdict for a file of a revision, with the ignore files of the revision
+fn revision_verdict(walker: &FileWalker, revision: &Revision, file: &RevisionFile) -> Verdict {
+  walker.path_verdict(&file.relative, file.size, |path| {
+    walker.ignore_files.matching_rule_with(path, false, |ignore_file| revision.ignore_file(ignore_file))
+  })
+}
 
-        if ignore {  
-          entries.skip_current_dir(); continue;
-        }
-      }
+// --| Index File -------------------------------
+// --|-------------------------------------------
+/// Files that cannot be read are added to `Documents::errors`
+fn index_file(documents: &mut Documents, entry: &Path, metadata: &mut HashMap<String, Value>, settings: &config::Config, manifest: Option<&Manifest>) {
+  let bytes = match std::fs::read(entry) {
+    Ok(bytes) => bytes,
+    Err(err) => {
+      let root = PathBuf::from(settings.get_str("indexer.root").unwrap_or_default());
+      let relative = relative_path(entry, &root);
+      error!("Could not read {}: {}", &relative, err);
+      documents.errors.push(FileError { path: relative, error: err.to_string() });
+      return;
+    }
+  };
 
-      // --| Skip hidden
-      if is_hidden(&entry) {
-        if entry.file_type().is_dir() { entries.skip_current_dir(); }
-        continue;
-      }
+  index_content(documents, entry, bytes, metadata, settings, manifest);
+}
 
-      if entry.file_type().is_dir() { continue; }
+/// Binary files are skipped, text in other encodings than UTF-8 is
+/// transcoded
+fn index_content(documents: &mut Documents, entry: &Path, bytes: Vec<u8>, metadata: &mut HashMap<String, Value>, settings: &config::Config, manifest: Option<&Manifest>) {
+  let root = PathBuf::from(settings.get_str("indexer.root").unwrap_or_default());
+  let relative = relative_path(entry, &root);
+  let hash = content_hash(&bytes);
+
+  if let Some(manifest) = manifest {
+    if manifest.is_unchanged(&relative, &hash) {
+      debug!("Unchanged: {}", &relative);
+      documents.unchanged.push(relative);
+      return;
+    }
+  }
 
-      // --| Check for proper file extensions 
-      let extension = &entry.path().extension();
-      if extension.is_none() && config.extensions.len() > 0
-        && !config.extensions.contains(&"*".to_owned()) {
-          continue;
+  // --| Fragment byte ranges refer to the decoded text, the payload records
+  // --| how it differs from the file on disk
+  let content = match decode(&bytes) {
+    Decoded::Text { text, encoding, bom } => {
+      if encoding != "UTF-8" || bom > 0 {
+        debug!("Decoded {} from {}", &relative, encoding);
+        metadata.insert("encoding".to_owned(), Value::String(encoding.to_owned()));
       }
-
-      if let Some(extension) = extension {
-        let ext = &extension.to_str().unwrap().to_owned();
-        if !config.extensions.contains(&ext) { continue; }
+      if bom > 0 {
+        metadata.insert("bom_length".to_owned(), Value::from(bom));
       }
-
-      // --| Apply metadata to the document
-      let document = obtain_data(
-        entry.path().clone(), 
-        &mut metadata_store.metadata.clone(), 
-        &settings
-      );
-    
-      let path = entry.path().display().to_string();
-      let file = entry.path().file_stem().unwrap().to_str().unwrap().to_owned();
-
-      index.insert(path, file);
-      documents.add(document);
+      text
+    },
+    Decoded::Binary => {
+      info!("Skipping binary file: {}", &relative);
+      documents.skipped.push(relative);
+      return;
     }
-  }
+  };
 
-  documents
+  let document = obtain_data(entry, content, hash, relative, metadata, settings);
+  documents.add(document);
 }
 
 // --| Obtain Data ------------------------------
 // --|-------------------------------------------
-fn obtain_data(entry: &Path, metadata: &mut HashMap<String, Value>, settings: &config::Config) -> Document {
-  let content = std::fs::read_to_string(&entry).unwrap();
+fn obtain_data(entry: &Path, content: String, hash: String, relative: String, metadata: &mut HashMap<String, Value>, settings: &config::Config) -> Document {
   let extension: String;
 
   let path = entry.display().to_string();
-  let name = entry.file_name().expect("Should be able to get file name").to_str().unwrap().to_owned();
+  let name = entry.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
 
   if let Some(ext) = entry.extension() {
-    extension = ext.to_str().unwrap().to_owned();
+    extension = ext.to_string_lossy().into_owned();
   } else {
     extension = "".to_owned();
   }
 
-  let file_stem = entry.file_stem().expect("Should be able to get the file stem").to_str().unwrap().to_owned();
+  let file_stem = entry.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
 
   metadata.insert("path".to_owned(), Value::String(path.clone()));
   metadata.insert("file_name".to_owned(), Value::String(name.clone()));
   metadata.insert("extension".to_owned(), Value::String(extension.clone()));
   metadata.insert("file_stem".to_owned(), Value::String(file_stem.clone()));
 
+  let namespace = project_namespace(settings);
+  metadata.insert("project".to_owned(), Value::String(namespace.clone()));
+  metadata.insert("relative_path".to_owned(), Value::String(relative.clone()));
+
   let mut document = Document{
     name: name.clone(),
-    id: uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, name.clone().as_bytes()).to_string(),
+    id: document_id(&namespace, &relative),
+    path: relative,
+    hash,
     text: content,
     fragments: vec![],
     metadata : metadata.clone(),
   };
 
   debug!("Indexing: {}", &path);
-  let fragments = create_fragments_from_text(document.text.clone(), &settings);
+  let fragments = create_fragments(&document.text, &document.path, settings);
   for i in 0..fragments.len() {
-    document.add_fragment(fragments[i].clone(), i);
+    document.add_fragment(&fragments[i], i);
   }
 
  document
 }
 
 
+/// The `database.metadata` added to every document
+fn metadata_store(settings: &config::Config) -> MetaDataStore {
+  match settings.get_str("database.metadata") {
+    Ok(store) if !store.is_empty() => MetaDataStore::from_json(&store),
+    _ => MetaDataStore::new(),
+  }
+}
+
+// --| Document Id ------------------------------
+// --|-------------------------------------------
+/// The project namespace is `indexer.namespace` when set, otherwise the
+/// absolute project root. Set it explicitly to get the same ids for a
+/// project that is checked out in different locations. A git revision
+/// gets its own namespace, `<namespace>@<rev>`, so it does not replace the
+/// points of the working tree.
+pub fn project_namespace(settings: &config::Config) -> String {
+  let namespace = match settings.get_str("indexer.namespace") {
+    Ok(namespace) if !namespace.is_empty() => namespace,
+    _ => settings.get_str("indexer.root").unwrap_or_default(),
+  };
+
+  match settings.get_str("git.rev") {
+    Ok(rev) if !rev.is_empty() => format!("{}@{}", namespace, rev),
+    _ => namespace,
+  }
+}
+
+/// Stable id of a document: UUIDv5 of its project relative path, within a
+/// UUIDv5 namespace derived from the project
+pub fn document_id(namespace: &str, relative: &str) -> String {
+  let project = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, namespace.as_bytes());
+  uuid::Uuid::new_v5(&project, relative.as_bytes()).to_string()
+}
+
 pub fn _search_files(buffer: String, app_data_dir: PathBuf,) -> Result<HashMap<String, String>, Error> {
   let index_file = File::open(app_data_dir.as_path()).unwrap();
   let index: Index = serde_json::from_reader(index_file).expect("Should be able to read content");
@@ -259,3 +450,44 @@ pub fn _search_files(buffer: String, app_data_dir: PathBuf,) -> Result<HashMap<S
 
   Ok(search_results) 
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use git2::{Repository, Signature};
+
+  #[test]
+  fn git_files_are_selected_and_removed_listed() {
+    let directory = tempfile::tempdir().unwrap();
+    let root = directory.path().canonicalize().unwrap();
+    fs::create_dir(root.join("src")).unwrap();
+    fs::write(root.join("src/a.rs"), "fn a() {}").unwrap();
+    fs::write(root.join("src/b.rs"), "fn b() {}").unwrap();
+    fs::write(root.join("src/c.md"), "# C").unwrap();
+
+    let repository = Repository::init(&root).unwrap();
+    let mut index = repository.index().unwrap();
+    index.add_path(Path::new("src/a.rs")).unwrap();
+    index.add_path(Path::new("src/b.rs")).unwrap();
+    index.add_path(Path::new("src/c.md")).unwrap();
+    index.write().unwrap();
+    let tree = repository.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("test", "test@example.com").unwrap();
+    repository.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[]).unwrap();
+
+    fs::write(root.join("src/a.rs"), "fn a() { todo!() }").unwrap();
+    fs::write(root.join("src/c.md"), "# Not selected by the rules").unwrap();
+    fs::remove_file(root.join("src/b.rs")).unwrap();
+
+    let mut settings = config::Config::default();
+    settings.set("indexer.project", root.display().to_string()).unwrap();
+    settings.set("indexer.root", root.display().to_string()).unwrap();
+    settings.set("indexer.directories", vec!["src"]).unwrap();
+    settings.set("matcher.rules", vec!["*.rs"]).unwrap();
+
+    let (files, removed) = git_files(&GitSelection::Since("HEAD".to_string()), &settings).unwrap();
+    assert_eq!(files, vec![root.join("src/a.rs")]);
+    assert_eq!(removed, vec!["src/b.rs".to_string()]);
+  }
+}
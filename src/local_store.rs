@@ -0,0 +1,406 @@
+use std::fs;
+use simplelog::*;
+use std::sync::Mutex;
+use serde_json::Value;
+use async_trait::async_trait;
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use instant_distance::{Builder, HnswMap, Search};
+
+use crate::data_types::EmbeddedDocuments;
+use crate::configuration::get_config_dir;
+use crate::database::{Database, SearchQuery, SearchHit, StoredPoint, CollectionInfo, PayloadFilter, fragment_payload};
+
+// Candidates an HNSW search returns, deeper pages are searched exhaustively
+const HNSW_EF_SEARCH: usize = 100;
+
+// --| Local DataTypes ----------------
+// --|---------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexKind { BruteForce, Hnsw }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LocalPoint {
+  vector: Vec<f32>,
+  payload: HashMap<String, Value>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Collection {
+  dimensions: usize,
+  points: BTreeMap<String, LocalPoint>,
+
+  // --| Built on the first search after a change, and stored with the
+  // --| collection so later searches don't rebuild it
+  #[serde(default)]
+  hnsw: Option<HnswMap<Embedding, String>>,
+}
+
+/// Normalized vector, so cosine similarity is a dot product
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Embedding(Vec<f32>);
+
+impl Embedding {
+  fn new(vector: &[f32]) -> Self {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm == 0. { return Self(vector.to_vec()); }
+    Self(vector.iter().map(|v| v / norm).collect())
+  }
+
+  fn similarity(&self, other: &Self) -> f32 {
+    self.0.iter().zip(&other.0).map(|(a, b)| a * b).sum()
+  }
+}
+
+impl instant_distance::Point for Embedding {
+  fn distance(&self, other: &Self) -> f32 {
+    1. - self.similarity(other)
+  }
+}
+
+// --| Local Store --------------------
+// --|---------------------------------
+/// File-backed vector store for use without a database server. Every
+/// collection is a single json file within the store directory. Changes
+/// are kept in memory and each changed collection is written once, when
+/// the upload is flushed.
+pub struct LocalStore {
+  path: PathBuf,
+  index: IndexKind,
+  collections: Mutex<HashMap<String, Collection>>,
+  changed: Mutex<HashSet<String>>,
+}
+
+impl LocalStore {
+  /// Uses `database.path`, relative paths are resolved against the project
+  /// root. Defaults to ~/.config/vectorizer/store
+  pub fn open(settings: &config::Config) -> Result<Self> {
+    let path = match settings.get_str("database.path") {
+      Ok(path) if !path.is_empty() => {
+        let root = PathBuf::from(settings.get_str("indexer.root").unwrap_or_default());
+        root.join(path)
+      },
+      _ => get_config_dir("vectorizer").join("store"),
+    };
+
+    let index = match settings.get_str("database.index").unwrap_or_default().as_str() {
+      "hnsw" => IndexKind::Hnsw,
+      _ => IndexKind::BruteForce,
+    };
+
+    fs::create_dir_all(&path)?;
+    debug!("Using local store at {:?} ({:?})", &path, index);
+
+    Ok(Self { path, index, collections: Mutex::new(HashMap::new()), changed: Mutex::new(HashSet::new()) })
+  }
+
+  fn collection_path(&self, collection: &str) -> PathBuf {
+    self.path.join(format!("{}.json", collection))
+  }
+
+  /// Run `f` on a loaded collection, returns None if it does not exist
+  fn with_collection<T>(&self, collection: &str, f: impl FnOnce(&mut Collection) -> T) -> Result<Option<T>> {
+    let mut collections = self.collections.lock().unwrap();
+
+    if !collections.contains_key(collection) {
+      let path = self.collection_path(collection);
+      if !path.exists() { return Ok(None); }
+
+      let loaded: Collection = serde_json::from_str(&fs::read_to_string(&path)?)?;
+      collections.insert(collection.to_string(), loaded);
+    }
+
+    Ok(collections.get_mut(collection).map(f))
+  }
+
+  fn mark_changed(&self, collection: &str) {
+    self.changed.lock().unwrap().insert(collection.to_string());
+  }
+
+  fn save(&self, collection: &str) -> Result<()> {
+    let collections = self.collections.lock().unwrap();
+    if let Some(loaded) = collections.get(collection) {
+      write_collection(&self.collection_path(collection), loaded)?;
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Database for LocalStore {
+  async fn list_collections(&self) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(&self.path)? {
+      let path = entry?.path();
+      if path.extension().map(|e| e == "json").unwrap_or(false) {
+        if let Some(stem) = path.file_stem() { names.push(stem.to_string_lossy().to_string()); }
+      }
+    }
+    Ok(names)
+  }
+
+  async fn create_collection(&self, collection: &str, dimensions: usize) -> Result<()> {
+    let created = Collection { dimensions, ..Default::default() };
+    write_collection(&self.collection_path(collection), &created)?;
+    self.collections.lock().unwrap().insert(collection.to_string(), created);
+    Ok(())
+  }
+
+  async fn collection_info(&self, collection: &str) -> Result<Option<CollectionInfo>> {
+    self.with_collection(collection, |loaded| CollectionInfo {
+      name: collection.to_string(),
+      points: loaded.points.len() as u64,
+      dimensions: loaded.dimensions as u64,
+    })
+  }
+
+  async fn drop_collection(&self, collection: &str) -> Result<()> {
+    self.collections.lock().unwrap().remove(collection);
+    self.changed.lock().unwrap().remove(collection);
+    let path = self.collection_path(collection);
+    if path.exists() { fs::remove_file(path)?; }
+    Ok(())
+  }
+
+  async fn upsert(&self, documents: EmbeddedDocuments) -> Result<()> {
+    let collection = documents.collection.clone();
+
+    self.with_collection(&collection, |loaded| -> Result<()> {
+      for document in &documents.documents {
+        if document.embeddings.len() != loaded.dimensions {
+          return Err(anyhow!("Expected {} dimensions, got {} for {}", loaded.dimensions, document.embeddings.len(), document.id));
+        }
+
+        loaded.points.insert(document.id.clone(), LocalPoint {
+          vector: document.embeddings.clone(),
+          payload: fragment_payload(document, &documents.model),
+        });
+      }
+
+      loaded.hnsw = None;
+      Ok(())
+    })?.ok_or(anyhow!("Collection {} does not exist", collection))??;
+
+    self.mark_changed(&collection);
+    Ok(())
+  }
+
+  async fn delete(&self, collection: &str, fragment_ids: &[String]) -> Result<()> {
+    let deleted = self.with_collection(collection, |loaded| {
+      for id in fragment_ids { loaded.points.remove(id); }
+      loaded.hnsw = None;
+    })?;
+
+    if deleted.is_some() { self.mark_changed(collection); }
+    Ok(())
+  }
+
+  async fn search(&self, query: SearchQuery) -> Result<Vec<SearchHit>> {
+    let index = self.index;
+    let target = Embedding::new(&query.vector);
+    let depth = query.offset.unwrap_or(0) as usize + query.limit as usize;
+    let mut built = false;
+
+    let hits = self.with_collection(&query.collection, |loaded| {
+      // --| Payload filters, and pages past the HNSW candidates, are applied on a full scan
+      let candidates: Vec<(String, f32)> = if index == IndexKind::Hnsw && query.filter.is_none() && depth <= HNSW_EF_SEARCH {
+        let hnsw = loaded.hnsw.get_or_insert_with(|| {
+          built = true;
+          build_hnsw(&loaded.points)
+        });
+        let mut search = Search::default();
+        hnsw.search(&target, &mut search)
+          .map(|item| (item.value.clone(), 1. - item.distance))
+          .collect()
+      } else {
+        let mut scored = loaded.points.iter()
+          .filter(|(_, point)| matches_filter(&point.payload, &query.filter))
+          .map(|(id, point)| (id.clone(), target.similarity(&Embedding::new(&point.vector))))
+          .collect::<Vec<_>>();
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        scored
+      };
+
+      candidates.into_iter()
+        .filter(|(_, score)| query.score_threshold.map(|t| *score >= t).unwrap_or(true))
+        .skip(query.offset.unwrap_or(0) as usize)
+        .take(query.limit as usize)
+        .map(|(id, score)| SearchHit { payload: loaded.points[&id].payload.clone(), id, score })
+        .collect::<Vec<_>>()
+    })?;
+
+    // --| Searches don't belong to an upload, so a new index is written right away
+    if built { self.save(&query.collection)?; }
+    Ok(hits.unwrap_or_default())
+  }
+
+  async fn scroll(&self, collection: &str, filter: Option<PayloadFilter>) -> Result<Vec<StoredPoint>> {
+    let points = self.with_collection(collection, |loaded| {
+      loaded.points.iter()
+        .filter(|(_, point)| matches_filter(&point.payload, &filter))
+        .map(|(id, point)| StoredPoint { id: id.clone(), payload: point.payload.clone() })
+        .collect::<Vec<_>>()
+    })?;
+
+    Ok(points.unwrap_or_default())
+  }
+
+  async fn flush(&self) -> Result<()> {
+    let changed = std::mem::take(&mut *self.changed.lock().unwrap());
+    for collection in changed {
+      debug!("Writing collection {}", collection);
+      self.save(&collection)?;
+    }
+    Ok(())
+  }
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+fn write_collection(path: &Path, collection: &Collection) -> Result<()> {
+  // --| Write to a temporary file first so a crash can't corrupt the store
+  let tmp_path = path.with_extension("json.tmp");
+  fs::write(&tmp_path, serde_json::to_string(collection)?)?;
+  fs::rename(&tmp_path, path)?;
+  Ok(())
+}
+
+fn build_hnsw(points: &BTreeMap<String, LocalPoint>) -> HnswMap<Embedding, String> {
+  let (embeddings, ids): (Vec<_>, Vec<_>) = points.iter()
+    .map(|(id, point)| (Embedding::new(&point.vector), id.clone()))
+    .unzip();
+
+  Builder::default().ef_search(HNSW_EF_SEARCH).build(embeddings, ids)
+}
+
+fn matches_filter(payload: &HashMap<String, Value>, filter: &Option<PayloadFilter>) -> bool {
+  match filter {
+    Some(filter) => filter.iter().all(|(key, value)| payload.get(key) == Some(value)),
+    None => true,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data_types::{EmbeddedDocument, TextRange};
+
+  fn store(path: &Path, index: IndexKind) -> LocalStore {
+    LocalStore { path: path.to_path_buf(), index, collections: Mutex::new(HashMap::new()), changed: Mutex::new(HashSet::new()) }
+  }
+
+  fn fragments(vectors: &[(&str, &str, Vec<f32>)]) -> EmbeddedDocuments {
+    let mut documents = EmbeddedDocuments::new();
+    documents.collection = "test".to_string();
+    documents.dimensions = 2;
+    for (id, document_id, vector) in vectors {
+      documents.documents.push(EmbeddedDocument {
+        id: id.to_string(),
+        document_id: document_id.to_string(),
+        name: document_id.to_string(),
+        text: id.to_string(),
+        range: TextRange::default(),
+        embeddings: vector.clone(),
+        metadata: HashMap::new(),
+      });
+    }
+    documents
+  }
+
+  fn query(vector: Vec<f32>, filter: Option<PayloadFilter>) -> SearchQuery {
+    SearchQuery { collection: "test".to_string(), vector, limit: 10, filter, ..Default::default() }
+  }
+
+  fn ids(hits: &[SearchHit]) -> Vec<&str> {
+    hits.iter().map(|hit| hit.id.as_str()).collect()
+  }
+
+  async fn filled_store(path: &Path, index: IndexKind) -> LocalStore {
+    let local = store(path, index);
+    local.create_collection("test", 2).await.unwrap();
+    local.upsert(fragments(&[
+      ("a_0", "a", vec![1., 0.]),
+      ("a_1", "a", vec![0.6, 0.8]),
+      ("b_0", "b", vec![0., 1.]),
+    ])).await.unwrap();
+    local
+  }
+
+  #[tokio::test]
+  async fn search_orders_by_similarity() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = filled_store(dir.path(), IndexKind::BruteForce).await;
+
+    let hits = local.search(query(vec![1., 0.1], None)).await.unwrap();
+    assert_eq!(ids(&hits), vec!["a_0", "a_1", "b_0"]);
+
+    let hits = local.search(SearchQuery { offset: Some(1), limit: 1, ..query(vec![1., 0.1], None) }).await.unwrap();
+    assert_eq!(ids(&hits), vec!["a_1"]);
+  }
+
+  #[tokio::test]
+  async fn search_and_scroll_apply_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = filled_store(dir.path(), IndexKind::BruteForce).await;
+    let filter: PayloadFilter = HashMap::from([("document_id".to_string(), Value::from("b"))]);
+
+    let hits = local.search(query(vec![1., 0.], Some(filter.clone()))).await.unwrap();
+    assert_eq!(ids(&hits), vec!["b_0"]);
+
+    let points = local.scroll("test", Some(filter)).await.unwrap();
+    assert_eq!(points.iter().map(|point| point.id.as_str()).collect::<Vec<_>>(), vec!["b_0"]);
+  }
+
+  #[tokio::test]
+  async fn upsert_rejects_wrong_dimensions() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = filled_store(dir.path(), IndexKind::BruteForce).await;
+
+    assert!(local.upsert(fragments(&[("c_0", "c", vec![1., 0., 0.])])).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn changes_are_written_on_flush() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = filled_store(dir.path(), IndexKind::BruteForce).await;
+    local.delete("test", &["a_1".to_string()]).await.unwrap();
+
+    // --| Only the empty collection is on disk until the upload is flushed
+    let reopened = store(dir.path(), IndexKind::BruteForce);
+    assert_eq!(reopened.collection_info("test").await.unwrap().unwrap().points, 0);
+
+    local.flush().await.unwrap();
+    let reopened = store(dir.path(), IndexKind::BruteForce);
+    assert_eq!(reopened.collection_info("test").await.unwrap().unwrap().points, 2);
+    let hits = reopened.search(query(vec![1., 0.], None)).await.unwrap();
+    assert_eq!(ids(&hits), vec!["a_0", "b_0"]);
+  }
+
+  #[tokio::test]
+  async fn hnsw_index_is_stored_with_collection() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = filled_store(dir.path(), IndexKind::Hnsw).await;
+    local.flush().await.unwrap();
+
+    let hits = local.search(query(vec![0.1, 1.], None)).await.unwrap();
+    assert_eq!(ids(&hits), vec!["b_0", "a_1", "a_0"]);
+
+    let reopened = store(dir.path(), IndexKind::Hnsw);
+    assert!(reopened.with_collection("test", |loaded| loaded.hnsw.is_some()).unwrap().unwrap());
+    let hits = reopened.search(query(vec![0.1, 1.], None)).await.unwrap();
+    assert_eq!(ids(&hits), vec!["b_0", "a_1", "a_0"]);
+  }
+
+  #[tokio::test]
+  async fn drop_collection_removes_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let local = filled_store(dir.path(), IndexKind::BruteForce).await;
+    local.drop_collection("test").await.unwrap();
+    local.flush().await.unwrap();
+
+    assert!(local.list_collections().await.unwrap().is_empty());
+    assert!(local.collection_info("test").await.unwrap().is_none());
+  }
+}
@@ -50,8 +50,12 @@ mod macros {
             eprintln!("Couldn't write to file: {}", e);
           }
         }}
-        else {
+        // --| Structured output must not be mixed with timing lines on stdout
+        else if crate::output::OutputFormat::from_settings().is_text() {
           println!($($arg)*);
+        }
+        else {
+          eprintln!($($arg)*);
         })
   }
 }
@@ -1,5 +1,5 @@
 use std::env;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use data_types::ModelLocation;
 use simplelog::*;
 use std::fs::File;
@@ -8,16 +8,28 @@ use std::str::FromStr;
 use std::sync::RwLock;
 use std::path::PathBuf;
 use anyhow::{anyhow, Result, Error};
-use qdrant_client::prelude::*;
+use path_absolutize::Absolutize;
 
 mod cli;
+mod code;
+mod chunker;
 mod model;
 mod macros;
 mod qdrant;
+mod search;
+mod output;
+mod git;
+mod upload;
+mod watch;
 mod walker;
 mod indexer;
 mod matcher;
+mod markdown;
+mod manifest;
+mod daemon;
 mod database;
+mod decode;
+mod local_store;
 mod fragments;
 mod vectorize;
 mod data_types;
@@ -25,9 +37,14 @@ mod configuration;
 
 use crate::cli::cli;
 use crate::data_types::Arguments;
-use crate::qdrant::{test_connection, add_documents, SearchData, search_documents};
+use crate::database::{test_connection, print_connection_test};
+use crate::upload::{UploadOptions, print_summary};
+use crate::output::{OutputFormat, print_list, print_item};
+use crate::search::{SearchOptions, print_results};
+use crate::watch::{WatchOptions, DEFAULT_DEBOUNCE_MS};
 use crate::configuration::{get_system_config, default_project_settings};
 use crate::vectorize::Model;
+use crate::model::{SPECIAL_TOKENS, model_max_sequence_length};
 
 #[macro_use]
 extern crate lazy_static;
@@ -49,7 +66,9 @@ async fn main() -> Result<(), Error> {
   }
 
   // --| Logging ------------
-  init_logging(settings_path);
+  // --| Structured output keeps stdout clean by logging to stderr only
+  let text_output = args.output.as_ref().map(|o| o == "text").unwrap_or(true);
+  init_logging(settings_path, if text_output { TerminalMode::Mixed } else { TerminalMode::Stderr });
 
   // --| Settings -----------
   let mut settings = SETTINGS.write().unwrap();
@@ -61,19 +80,18 @@ async fn main() -> Result<(), Error> {
     // Early out if missing settings, but gives warning
     return Ok(())
   }
+
+  // --| The model daemon serves every project, it needs no project path
+  if let Some(("serve-model", _)) = matches.subcommand() {
+    drop(settings);
+    info!("Starting the model daemon");
+    return daemon::serve().await;
+  }
   
   // --| Project Path -------
   check_project(&args.clone(), &mut settings)?;
   debug!("{:?}", &settings);
 
-  let config: QdrantClientConfig;
-  if let Ok(url) = &settings.get_str("database.url") {
-    config = QdrantClientConfig::from_url( url);  
-  } else {
-    error!("No database url provided");
-    return Err(anyhow!("No database url provided"));
-  }
-
   // --| Ensure necessary settings are present
   if !verify_settings(&settings).is_ok(){
     return Ok(());
@@ -81,48 +99,71 @@ async fn main() -> Result<(), Error> {
 
   drop(settings);
  
-  let client = QdrantClient::new(Some(config)).await?;
+  let database = database::connect().await?;
+  let output = OutputFormat::from_settings();
 
   match matches.subcommand() {
 
     // --| Index and Upload --------
-    Some(("upload", _)) => {
-      let upload_start = Instant::now();
+    Some(("upload", upload_args)) => {
       info!("Uploading files");
 
+      let options = UploadOptions {
+        force: upload_args.get_flag("force"),
+        prune: upload_args.get_flag("prune"),
+      };
 
-      let index_start = Instant::now();
-      let documents = indexer::build_index();
-      if documents.documents.len() == 0 { 
-        warn!("No documents found");
-        return Ok(()); 
-      }
+      // --| A running daemon uploads with its model, nothing to load here
+      let socket = daemon::running(&SETTINGS.read().unwrap());
+      let summary = match socket {
+        Some(socket) => daemon::upload(&socket, &options).await?,
+        None => upload::upload(database.as_ref(), options).await?,
+      };
+      print_item(output, &summary, print_summary);
 
-      let (_handle, model) = Model::spawn(); 
-      perf!("Indexing time: {:?}", index_start.elapsed());
+      if !summary.failures.is_empty() {
+        return Err(anyhow!("{} files failed to upload", summary.failures.len()));
+      }
+    },
 
-      let embed_start = Instant::now();
-      let doc_embeds = model.encode(documents).await;
-      perf!("Embedding time: {:?}", embed_start.elapsed());
-      debug!("{:?}", &doc_embeds);
+    // --| Watch and Upload -------
+    Some(("watch", watch_args)) => {
+      info!("Watching files");
 
-      let add_start = Instant::now();
-      add_documents(client, doc_embeds?.clone()).await?;  
-      perf!("Upload time: {:?}", add_start.elapsed());
+      let options = WatchOptions {
+        upload: UploadOptions {
+          force: watch_args.get_flag("force"),
+          prune: watch_args.get_flag("prune"),
+        },
+        debounce: Duration::from_millis(*watch_args.get_one::<u64>("debounce").unwrap_or(&DEFAULT_DEBOUNCE_MS)),
+      };
 
-      perf!("Processing time: {:?}", upload_start.elapsed());
+      watch::watch(database.as_ref(), options, output).await?;
     },
 
     // --| Index -----------------
+    Some(("index", index_args)) if index_args.get_flag("explain") => {
+      info!("Explaining the index");
+      let explanations = indexer::explain()?;
+      print_list(output, &explanations, indexer::print_explanations);
+    },
+
     Some(("index", _)) => {
      info!("Indexing files"); 
-     let _documents = indexer::build_index();
+     let documents = indexer::build_index(None)?;
+
+     print_list(output, &documents.documents, |documents| {
+       for document in documents {
+         println!("{} ({} fragments)", document.path, document.fragments.len());
+       }
+     });
     },
     
     // --| Test Connection --------
     Some(("test", _)) => {
       info!("Testing connection");
-      test_connection(client).await?;
+      let report = test_connection(database.as_ref()).await?;
+      print_item(output, &report, print_connection_test);
     },
     
     // --| Search ----------------
@@ -138,14 +179,18 @@ async fn main() -> Result<(), Error> {
         return Ok(());
       }
 
-      info!("Searching");
-      let search_data = SearchData { 
-        search_term: search_term.to_string(),
-        ..Default::default() 
+      let options = SearchOptions {
+        term: search_term,
+        collection: SETTINGS.read().unwrap().get_str("database.collection").unwrap_or_default(),
+        limit: *args.get_one::<u64>("limit").unwrap_or(&10),
+        offset: args.get_one::<u64>("offset").cloned(),
+        score_threshold: args.get_one::<f32>("score_threshold").cloned(),
       };
 
-      let _results = search_documents(client, search_data).await?;
-
+      // --| Embed the query with the same model used for upload
+      let model = Model::load();
+      let results = search::search(database.as_ref(), &model, options).await?;
+      print_list(output, &results, print_results);
     },
     _ => unreachable!(),
   }
@@ -158,12 +203,20 @@ async fn main() -> Result<(), Error> {
 // --|-------------------------------------------
 pub fn check_settings(args: &mut Arguments, settings: &mut config::Config){
   if args.location.is_none() {
-    if let Ok(local) = settings.get_str("model.local") {
+    // --| model.local is a flag for model.location, older configs use it as the path
+    let location = settings.get_str("model.location").ok();
+    let local = match settings.get_bool("model.local") {
+      Ok(local) => if local { location.clone() } else { None },
+      Err(_) => settings.get_str("model.local").ok(),
+    };
+
+    if let Some(path) = local {
       args.location = Some(ModelLocation::Local);
-      args.location_path = Some(local.to_string());
+      args.location_path = Some(path);
     }
      else {
        args.location = Some(ModelLocation::Remote);
+       if let Some(remote) = location { args.remote = Some(remote); }
      }
   }
 }
@@ -186,6 +239,17 @@ pub fn check_project(args: &Arguments, settings: &mut config::Config) -> Result<
 
   settings.set("indexer.project", project.to_str().unwrap()).unwrap();
 
+  // --| Project root, used for relative paths and the manifest. When a single
+  // --| file is passed, the working directory is the root if it contains it
+  let absolute = project.absolutize()?.to_path_buf();
+  let root = if absolute.is_dir() { absolute } else {
+    let cwd = get_current_working_dir();
+    if absolute.starts_with(&cwd) { cwd } 
+    else { absolute.parent().map(|p| p.to_path_buf()).unwrap_or(cwd) }
+  };
+
+  settings.set("indexer.root", root.to_str().unwrap()).unwrap();
+
   let mut vector_file = project.join(".vectorizer");
 
   if settings.get_bool("indexer.project_file").unwrap_or(false) {
@@ -222,36 +286,41 @@ pub fn verify_settings(settings: &config::Config) -> Result<(), Error> {
     }
   }
 
+  // --| The local store does not need a server
+  let backend = settings.get_str("database.backend").unwrap_or("qdrant".to_string());
   match settings.get_str("database.url") {
     Ok(_) => {}
+    Err(_) if backend == "local" => {}
     Err(_) => {
       error!("No database url provided");
       return Err(anyhow!("No database url provided"));
     }
   }
 
-  if !settings.get_bool("indexer.is_file").ok().is_some() {
-    debug!("Is directory, check for extensions");
-    match settings.get_array("indexer.extensions") {
-      Ok(exts) => {
-        if exts.len() == 0 {
-          warn!("No extensions provided. Please provide the file extensions you wish to upload.");
-          return Err(anyhow!("No extensions provided. Please provide the file extensions you wish to upload."));
-        }
-      }
-      Err(_) => {
-        warn!("No extensions provided. Please provide the file extensions you wish to upload.");
-        return Err(anyhow!("No extensions provided. Please provide the file extensions you wish to upload."));
-      }
+  // --| The override can only lower the limit of the model
+  if let Ok(length) = settings.get_int("model.max_sequence_length") {
+    if length != 0 && length <= SPECIAL_TOKENS as i64 {
+      error!("model.max_sequence_length must be above {}", SPECIAL_TOKENS);
+      return Err(anyhow!("model.max_sequence_length must be above {}", SPECIAL_TOKENS));
+    }
+
+    let limit = model_max_sequence_length(settings);
+    if length > limit as i64 {
+      warn!("model.max_sequence_length {} is above the limit of the model, using {}", length, limit);
     }
-  } else { debug !("Is file, skip extension check"); }
+  }
+
+  // --| No extensions, or an empty list, index files of every extension
+  if settings.get_array("indexer.extensions").map(|exts| exts.is_empty()).unwrap_or(true) {
+    debug!("No extensions provided, indexing all files");
+  }
 
   Ok(())
 }
 
 // --| Initialize logging -----------------------
 // --|-------------------------------------------
-pub fn init_logging(config_path: PathBuf) -> PathBuf {
+pub fn init_logging(config_path: PathBuf, terminal_mode: TerminalMode) -> PathBuf {
   let mut default_level = LevelFilter::Warn;
   let settings = SETTINGS.write().unwrap();
 
@@ -270,7 +339,7 @@ pub fn init_logging(config_path: PathBuf) -> PathBuf {
 
   CombinedLogger::init(
     vec![
-    TermLogger::new(default_level, logging_config.clone(), TerminalMode::Mixed, ColorChoice::Auto),
+    TermLogger::new(default_level, logging_config.clone(), terminal_mode, ColorChoice::Auto),
     WriteLogger::new(default_level, logging_config, File::create(log_path).unwrap()),
   ]).unwrap();
 
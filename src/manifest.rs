@@ -0,0 +1,364 @@
+use std::fs;
+use simplelog::*;
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use serde::{Deserialize, Serialize};
+use path_absolutize::Absolutize;
+
+use crate::data_types::Documents;
+use crate::indexer::project_namespace;
+use crate::model::configured_max_sequence_length;
+use crate::configuration::get_config_dir;
+
+// --| Embedding Config ---------------
+// --|---------------------------------
+/// The settings that affect the produced embeddings and their ids, and the
+/// store they were uploaded to. If any of these change, every file in the
+/// manifest has to be uploaded again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EmbeddingConfig {
+  #[serde(default)]
+  pub backend: String,
+  pub local: bool,
+  pub location: String,
+  #[serde(default)]
+  pub max_sequence_length: usize,
+  #[serde(default)]
+  pub tokenizer: String,
+  pub max_tokens: i64,
+  #[serde(default)]
+  pub overlap: String,
+  #[serde(default)]
+  pub chunking: String,
+  #[serde(default)]
+  pub header: String,
+  pub collection: String,
+  #[serde(default)]
+  pub namespace: String,
+  #[serde(default)]
+  pub database_backend: String,
+  #[serde(default)]
+  pub database_url: String,
+  #[serde(default)]
+  pub database_path: String,
+}
+
+impl EmbeddingConfig {
+  pub fn from_settings(settings: &config::Config) -> Self {
+    Self {
+      backend: settings.get_str("model.backend").unwrap_or("rust-bert".to_string()),
+      local: settings.get_bool("model.local").unwrap_or(false),
+      location: settings.get_str("model.location").unwrap_or_default(),
+      max_sequence_length: configured_max_sequence_length(settings),
+      tokenizer: settings.get_str("model.tokenizer").unwrap_or_default(),
+      max_tokens: settings.get_int("database.max_tokens").unwrap_or(0),
+      overlap: settings.get_str("database.overlap").unwrap_or_default(),
+      chunking: settings.get::<serde_json::Value>("chunking").map(|c| c.to_string()).unwrap_or_default(),
+      header: settings.get_str("database.header").unwrap_or_default(),
+      collection: settings.get_str("database.collection").unwrap_or_default(),
+      namespace: project_namespace(settings),
+      database_backend: settings.get_str("database.backend").unwrap_or("qdrant".to_string()),
+      database_url: settings.get_str("database.url").unwrap_or_default(),
+      database_path: settings.get_str("database.path").unwrap_or_default(),
+    }
+  }
+}
+
+// --| Manifest Entry -----------------
+// --|---------------------------------
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ManifestEntry {
+  pub hash: String,
+  pub document_id: String,
+  pub fragments: Vec<String>,
+}
+
+// --| Prune Report -------------------
+// --|---------------------------------
+/// Points that no longer belong to any file or fragment in the project
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PruneReport {
+  pub removed_files: Vec<String>,
+  pub removed_fragments: Vec<String>,
+}
+
+impl PruneReport {
+  pub fn is_empty(&self) -> bool {
+    self.removed_files.is_empty() && self.removed_fragments.is_empty()
+  }
+
+  /// Don't delete these fragments, used for files that failed to upload
+  pub fn keep_fragments(&mut self, fragments: &[String]) {
+    self.removed_fragments.retain(|id| !fragments.contains(id));
+  }
+}
+
+// --| Manifest -----------------------
+// --|---------------------------------
+/// Per-project record of what has already been uploaded, keyed by the
+/// file path relative to the project root.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+  #[serde(skip)]
+  pub path: PathBuf,
+  pub embedding: EmbeddingConfig,
+  pub files: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+  /// Load the manifest for the current project and collection. A missing
+  /// or unreadable manifest is empty, and one created with a different
+  /// embedding config has its hashes reset, so every file gets uploaded.
+  pub fn load(settings: &config::Config) -> Self {
+    let path = Self::manifest_path(settings);
+    let embedding = EmbeddingConfig::from_settings(settings);
+
+    let mut manifest = match fs::read_to_string(&path) {
+      Ok(content) => match serde_json::from_str::<Manifest>(&content) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+          warn!("Could not parse manifest {:?}, starting fresh: {}", path, err);
+          Manifest::default()
+        }
+      },
+      Err(_) => Manifest::default(),
+    };
+
+    // --| Keep the entries so their old fragments can still be found as stale
+    if manifest.embedding != embedding {
+      if !manifest.files.is_empty() {
+        info!("Embedding config changed, all files will be uploaded again");
+      }
+      manifest.files.values_mut().for_each(|entry| entry.hash.clear());
+      manifest.embedding = embedding;
+    }
+
+    manifest.path = path;
+    manifest
+  }
+
+  /// Location of the manifest: ~/.config/vectorizer/manifests/<project>.json.
+  /// A git revision has a manifest of its own.
+  pub fn manifest_path(settings: &config::Config) -> PathBuf {
+    let root = settings.get_str("indexer.root").unwrap_or_default();
+    let collection = settings.get_str("database.collection").unwrap_or_default();
+    let mut key = format!("{}:{}", collection, root);
+    if let Ok(rev) = settings.get_str("git.rev") {
+      if !rev.is_empty() { key = format!("{}@{}", key, rev); }
+    }
+    let name = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, key.as_bytes()).to_string();
+
+    get_config_dir("vectorizer").join("manifests").join(format!("{}.json", name))
+  }
+
+  pub fn is_unchanged(&self, path: &str, hash: &str) -> bool {
+    self.files.get(path).map(|entry| entry.hash == hash).unwrap_or(false)
+  }
+
+  /// Files recorded in the manifest that were not seen by this run. Only a
+  /// directory run sees the whole project, so a single file run never
+  /// reports anything as missing. Files that could not be read still exist.
+  /// A run limited to a git diff only misses the files the diff removed.
+  pub fn missing_files(&self, documents: &Documents, is_file: bool) -> Vec<String> {
+    if is_file { return vec![]; }
+    if documents.partial { return documents.removed.clone(); }
+
+    self.files.keys()
+      .filter(|path| {
+        !documents.unchanged.contains(path) 
+          && !documents.documents.iter().any(|d| &d.path == *path)
+          && !documents.errors.iter().any(|e| &e.path == *path)
+      })
+      .cloned()
+      .collect()
+  }
+
+  /// Work out which fragments are stale. Fragment indexes past the new end
+  /// of a re-indexed document are always stale; fragments of missing files
+  /// are only included when `prune` is set.
+  pub fn plan_prune(&self, documents: &Documents, missing: &[String], prune: bool) -> PruneReport {
+    let mut report = PruneReport::default();
+
+    for document in &documents.documents {
+      if let Some(entry) = self.files.get(&document.path) {
+        let current = document.fragments.iter().map(|f| &f.id).collect::<Vec<_>>();
+        report.removed_fragments.extend(
+          entry.fragments.iter().filter(|id| !current.contains(id)).cloned()
+        );
+      }
+    }
+
+    if prune {
+      for path in missing {
+        if let Some(entry) = self.files.get(path) {
+          report.removed_files.push(path.clone());
+          report.removed_fragments.extend(entry.fragments.iter().cloned());
+        }
+      }
+    }
+
+    report
+  }
+
+  /// Forget the files that were pruned from the database
+  pub fn apply_prune(&mut self, report: &PruneReport) {
+    for path in &report.removed_files {
+      self.files.remove(path);
+    }
+  }
+
+  /// Manifest entries of the documents keyed by document id, together with
+  /// their path. They are recorded once the documents have been uploaded.
+  pub fn entries(documents: &Documents) -> HashMap<String, (String, ManifestEntry)> {
+    documents.documents.iter().map(|document| {
+      let entry = ManifestEntry {
+        hash: document.hash.clone(),
+        document_id: document.id.clone(),
+        fragments: document.fragments.iter().map(|f| f.id.clone()).collect(),
+      };
+      (document.id.clone(), (document.path.clone(), entry))
+    }).collect()
+  }
+
+  pub fn save(&self) -> Result<()> {
+    if let Some(parent) = self.path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+
+    let content = serde_json::to_string_pretty(&self)?;
+    fs::write(&self.path, content)?;
+    debug!("Manifest saved to {:?}", &self.path);
+    Ok(())
+  }
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+pub fn content_hash(content: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(content))
+}
+
+/// Path of `entry` relative to the project root, falling back to the full
+/// path when the file lives outside of it.
+pub fn relative_path(entry: &Path, root: &Path) -> String {
+  let entry = entry.absolutize().map(|p| p.to_path_buf()).unwrap_or_else(|_| entry.to_path_buf());
+
+  match entry.strip_prefix(root) {
+    Ok(relative) => relative.to_string_lossy().replace('\\', "/"),
+    Err(_) => entry.display().to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::data_types::{Document, DocumentFragment};
+
+  fn document(path: &str, hash: &str, fragments: usize) -> Document {
+    let mut document = Document {
+      id: format!("id-{}", path),
+      name: path.to_string(),
+      path: path.to_string(),
+      hash: hash.to_string(),
+      text: String::new(),
+      metadata: HashMap::new(),
+      fragments: vec![],
+    };
+
+    for index in 0..fragments {
+      let mut fragment = DocumentFragment::new();
+      fragment.id = format!("{}_{}", document.id, index);
+      document.fragments.push(fragment);
+    }
+    document
+  }
+
+  fn documents(documents: Vec<Document>) -> Documents {
+    let mut collected = Documents::new();
+    for document in documents { collected.add(document); }
+    collected
+  }
+
+  fn record(manifest: &mut Manifest, documents: &Documents) {
+    for (path, entry) in Manifest::entries(documents).into_values() { manifest.files.insert(path, entry); }
+  }
+
+  #[test]
+  fn unchanged_file_is_skipped() {
+    let mut manifest = Manifest::default();
+    record(&mut manifest, &documents(vec![document("src/a.rs", "hash-a", 2)]));
+
+    assert!(manifest.is_unchanged("src/a.rs", "hash-a"));
+    assert_eq!(manifest.files["src/a.rs"].fragments, vec!["id-src/a.rs_0", "id-src/a.rs_1"]);
+  }
+
+  #[test]
+  fn changed_or_unknown_file_is_uploaded() {
+    let mut manifest = Manifest::default();
+    record(&mut manifest, &documents(vec![document("src/a.rs", "hash-a", 1)]));
+
+    assert!(!manifest.is_unchanged("src/a.rs", "hash-b"));
+    assert!(!manifest.is_unchanged("src/b.rs", "hash-a"));
+
+    record(&mut manifest, &documents(vec![document("src/a.rs", "hash-b", 1)]));
+    assert!(manifest.is_unchanged("src/a.rs", "hash-b"));
+  }
+
+  #[test]
+  fn removed_file_is_missing() {
+    let mut manifest = Manifest::default();
+    record(&mut manifest, &documents(vec![document("src/a.rs", "hash-a", 1), document("src/b.rs", "hash-b", 2)]));
+
+    let mut run = documents(vec![]);
+    run.unchanged.push("src/a.rs".to_string());
+    let missing = manifest.missing_files(&run, false);
+    assert_eq!(missing, vec!["src/b.rs"]);
+
+    // --| Points of missing files are only removed with prune
+    assert!(manifest.plan_prune(&run, &missing, false).is_empty());
+
+    let report = manifest.plan_prune(&run, &missing, true);
+    assert_eq!(report.removed_files, vec!["src/b.rs"]);
+    assert_eq!(report.removed_fragments, vec!["id-src/b.rs_0", "id-src/b.rs_1"]);
+
+    manifest.apply_prune(&report);
+    assert!(!manifest.files.contains_key("src/b.rs"));
+  }
+
+  #[test]
+  fn shrunken_file_loses_trailing_fragments() {
+    let mut manifest = Manifest::default();
+    record(&mut manifest, &documents(vec![document("src/a.rs", "hash-a", 3)]));
+
+    let run = documents(vec![document("src/a.rs", "hash-b", 1)]);
+    let missing = manifest.missing_files(&run, false);
+    assert!(missing.is_empty());
+
+    let report = manifest.plan_prune(&run, &missing, false);
+    assert!(report.removed_files.is_empty());
+    assert_eq!(report.removed_fragments, vec!["id-src/a.rs_1", "id-src/a.rs_2"]);
+  }
+
+  #[test]
+  fn single_file_run_misses_nothing() {
+    let mut manifest = Manifest::default();
+    record(&mut manifest, &documents(vec![document("src/a.rs", "hash-a", 1), document("src/b.rs", "hash-b", 1)]));
+
+    let run = documents(vec![document("src/a.rs", "hash-c", 1)]);
+    assert!(manifest.missing_files(&run, true).is_empty());
+    assert_eq!(manifest.missing_files(&run, false), vec!["src/b.rs"]);
+  }
+
+  #[test]
+  fn failed_file_keeps_stale_fragments() {
+    let mut manifest = Manifest::default();
+    record(&mut manifest, &documents(vec![document("src/a.rs", "hash-a", 3), document("src/b.rs", "hash-b", 2)]));
+
+    let run = documents(vec![document("src/a.rs", "hash-c", 1), document("src/b.rs", "hash-d", 1)]);
+    let mut report = manifest.plan_prune(&run, &[], false);
+    report.keep_fragments(&manifest.files["src/a.rs"].fragments);
+    assert_eq!(report.removed_fragments, vec!["id-src/b.rs_1"]);
+  }
+}
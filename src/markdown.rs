@@ -0,0 +1,270 @@
+use simplelog::*;
+use serde_json::Value;
+
+use crate::chunker::Chunker;
+use crate::fragments::{Fragment, Tokens};
+
+// Separates the headings of a breadcrumb
+const BREADCRUMB_SEPARATOR: &str = " > ";
+
+// --| Markdown Chunker ---------------
+// --|---------------------------------
+/// Split a markdown document on its headings. Sections larger than
+/// `max_tokens` are split between paragraphs, fenced code blocks are kept
+/// whole unless they are too large on their own, and so is YAML front
+/// matter at the top of the document. The heading path of each section is
+/// stored as `heading_path` in the fragment metadata, and prepended to the
+/// embedded text with `breadcrumbs`.
+pub struct MarkdownChunker {
+  pub breadcrumbs: bool,
+}
+
+impl Chunker for MarkdownChunker {
+  fn chunk(&self, document: &str, tokens: &Tokens) -> Vec<Fragment> {
+    let mut fragments: Vec<Fragment> = Vec::new();
+
+    for section in parse_sections(document) {
+      let path = section.headings.join(BREADCRUMB_SEPARATOR);
+
+      for mut fragment in tokens.merge(document, &section.blocks()) {
+        if fragment.text.trim().is_empty() { continue; }
+        if path.is_empty() { fragments.push(fragment); continue; }
+
+        fragment.metadata.insert("heading_path".to_owned(), Value::String(path.clone()));
+        if self.breadcrumbs {
+          fragment.header = format!("{}\n\n", path);
+        }
+        fragments.push(fragment);
+      }
+    }
+
+    fragments
+  }
+}
+
+// --| Section ------------------------
+// --|---------------------------------
+/// The text under a heading, up to the next heading. Blocks start at the
+/// heading, at each paragraph after a blank line and at each code fence.
+struct Section {
+  end: usize,
+  headings: Vec<String>,
+  boundaries: Vec<usize>,
+}
+
+impl Section {
+  fn new(start: usize, headings: Vec<String>) -> Self {
+    Self { end: start, headings, boundaries: vec![start] }
+  }
+
+  fn start(&self) -> usize { self.boundaries[0] }
+
+  fn add_boundary(&mut self, position: usize) {
+    if self.boundaries.last().map(|b| *b < position).unwrap_or(true) {
+      self.boundaries.push(position);
+    }
+  }
+
+  fn blocks(&self) -> Vec<(usize, usize)> {
+    self.boundaries.iter().enumerate()
+      .map(|(i, start)| (*start, self.boundaries.get(i + 1).copied().unwrap_or(self.end)))
+      .collect()
+  }
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+fn parse_sections(document: &str) -> Vec<Section> {
+  let mut sections: Vec<Section> = vec![Section::new(0, vec![])];
+  let mut headings: Vec<(usize, String)> = Vec::new();
+  let mut fence: Option<(char, usize)> = None;
+  let mut front_matter = document.lines().next().map(|line| line.trim_end() == "---").unwrap_or(false);
+  let mut in_block = false;
+  let mut previous_line = 0;
+  let mut position = 0;
+
+  for line in document.split_inclusive('\n') {
+    let start = position;
+    position += line.len();
+    let trimmed = line.trim_end();
+
+    // --| Front matter stays in the first block, its `#` lines are comments
+    if front_matter {
+      if start > 0 && (trimmed == "---" || trimmed == "...") { front_matter = false; }
+      previous_line = start;
+      continue;
+    }
+
+    // --| Nothing inside a code fence is a heading or a block boundary
+    if let Some((marker, length)) = fence {
+      if is_fence_close(trimmed, marker, length) {
+        fence = None;
+        in_block = false;
+      }
+      previous_line = start;
+      continue;
+    }
+
+    if let Some(open) = fence_open(trimmed) {
+      sections.last_mut().unwrap().add_boundary(start);
+      fence = Some(open);
+      in_block = true;
+      previous_line = start;
+      continue;
+    }
+
+    // --| A single line paragraph underlined with = or - is a heading too
+    let heading = atx_heading(trimmed).map(|heading| (start, heading)).or_else(|| {
+      let single_line = in_block && sections.last().unwrap().boundaries.last() == Some(&previous_line);
+      match single_line { true => setext_heading(trimmed, &document[previous_line..start]).map(|h| (previous_line, h)), false => None }
+    });
+
+    if let Some((heading_start, (level, title))) = heading {
+      while headings.last().map(|(l, _)| *l >= level).unwrap_or(false) { headings.pop(); }
+      headings.push((level, title));
+      let path: Vec<String> = headings.iter().map(|(_, t)| t.clone()).collect();
+
+      let current = sections.last_mut().unwrap();
+      current.end = heading_start;
+
+      // --| A heading directly followed by a sub heading joins its section
+      let empty = current.boundaries.iter().skip(1).all(|b| *b >= current.end);
+      let parent = !current.headings.is_empty() && current.headings[..] == path[..path.len() - 1];
+      let section_start = if empty && parent { sections.pop().unwrap().start() } else { heading_start };
+
+      sections.push(Section::new(section_start, path));
+      sections.last_mut().unwrap().add_boundary(heading_start);
+      in_block = false;
+      previous_line = start;
+      continue;
+    }
+
+    if trimmed.trim().is_empty() {
+      in_block = false;
+    } else if !in_block {
+      sections.last_mut().unwrap().add_boundary(start);
+      in_block = true;
+    }
+    previous_line = start;
+  }
+
+  if fence.is_some() { debug!("Unclosed code fence, kept until the end of the document"); }
+  sections.last_mut().unwrap().end = document.len();
+  sections
+}
+
+/// `# Title` to `###### Title`, indented by at most 3 spaces
+fn atx_heading(line: &str) -> Option<(usize, String)> {
+  let line = strip_indent(line)?;
+  let level = line.chars().take_while(|c| *c == '#').count();
+  if level == 0 || level > 6 { return None; }
+
+  let rest = &line[level..];
+  if !rest.is_empty() && !rest.starts_with(' ') && !rest.starts_with('\t') { return None; }
+
+  let title = rest.trim().trim_end_matches('#').trim().to_string();
+  Some((level, title))
+}
+
+/// `===` below a line is a level 1 heading, `---` a level 2 heading
+fn setext_heading(line: &str, title: &str) -> Option<(usize, String)> {
+  let line = strip_indent(line)?;
+  let title = title.trim();
+  if title.is_empty() || atx_heading(title).is_some() { return None; }
+
+  if !line.is_empty() && line.chars().all(|c| c == '=') { return Some((1, title.to_string())); }
+  if !line.is_empty() && line.chars().all(|c| c == '-') { return Some((2, title.to_string())); }
+  None
+}
+
+/// ``` or ~~~, at least three
+fn fence_open(line: &str) -> Option<(char, usize)> {
+  let line = strip_indent(line)?;
+  let marker = line.chars().next()?;
+  if marker != '`' && marker != '~' { return None; }
+
+  let length = line.chars().take_while(|c| *c == marker).count();
+  if length < 3 { return None; }
+  if marker == '`' && line[length..].contains('`') { return None; }
+  Some((marker, length))
+}
+
+fn is_fence_close(line: &str, marker: char, length: usize) -> bool {
+  match strip_indent(line) {
+    Some(line) => line.chars().take_while(|c| *c == marker).count() >= length && line.trim_start_matches(marker).trim().is_empty(),
+    None => false,
+  }
+}
+
+/// Block markers can be indented by up to 3 spaces, any more is code
+fn strip_indent(line: &str) -> Option<&str> {
+  let indent = line.chars().take_while(|c| *c == ' ').count();
+  if indent > 3 { return None; }
+  Some(&line[indent..])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sections(document: &str) -> Vec<(String, &str)> {
+    parse_sections(document).iter()
+      .map(|section| (section.headings.join(BREADCRUMB_SEPARATOR), &document[section.start()..section.end]))
+      .collect()
+  }
+
+  #[test]
+  fn headings_nest_into_paths() {
+    let document = "Intro\n\n# Install\n\nSteps\n\n## Linux\n\nApt\n\n### libtorch\n\nBuild\n\n## Mac\n\nBrew\n\n# Usage\n\nRun\n";
+    assert_eq!(sections(document), vec![
+      ("".to_string(), "Intro\n\n"),
+      ("Install".to_string(), "# Install\n\nSteps\n\n"),
+      ("Install > Linux".to_string(), "## Linux\n\nApt\n\n"),
+      ("Install > Linux > libtorch".to_string(), "### libtorch\n\nBuild\n\n"),
+      ("Install > Mac".to_string(), "## Mac\n\nBrew\n\n"),
+      ("Usage".to_string(), "# Usage\n\nRun\n"),
+    ]);
+  }
+
+  #[test]
+  fn heading_followed_by_sub_heading_joins_its_section() {
+    let document = "# Install\n## Linux\n\nApt\n";
+    assert_eq!(sections(document), vec![
+      ("".to_string(), ""),
+      ("Install > Linux".to_string(), "# Install\n## Linux\n\nApt\n"),
+    ]);
+  }
+
+  #[test]
+  fn setext_headings() {
+    let document = "Install\n=======\n\nSteps\n\nLinux\n-----\n\nApt\n";
+    assert_eq!(sections(document), vec![
+      ("".to_string(), ""),
+      ("Install".to_string(), "Install\n=======\n\nSteps\n\n"),
+      ("Install > Linux".to_string(), "Linux\n-----\n\nApt\n"),
+    ]);
+  }
+
+  #[test]
+  fn fenced_code_is_one_block() {
+    let document = "# Build\n\n```sh\n# not a heading\n\nmake\n```\n\nDone\n";
+    let parsed = parse_sections(document);
+
+    assert_eq!(parsed.len(), 2);
+    assert_eq!(parsed[1].headings, vec!["Build"]);
+    let blocks = parsed[1].blocks().iter().map(|(start, end)| &document[*start..*end]).collect::<Vec<_>>();
+    assert_eq!(blocks, vec!["# Build\n\n", "```sh\n# not a heading\n\nmake\n```\n\n", "Done\n"]);
+  }
+
+  #[test]
+  fn front_matter_is_not_a_heading() {
+    let document = "---\ntitle: Guide\n# draft: true\n---\n\n# Install\n\nSteps\n";
+    let parsed = parse_sections(document);
+
+    assert_eq!(sections(document), vec![
+      ("".to_string(), "---\ntitle: Guide\n# draft: true\n---\n\n"),
+      ("Install".to_string(), "# Install\n\nSteps\n"),
+    ]);
+    assert_eq!(parsed[0].blocks(), vec![(0, document.find("# Install").unwrap())]);
+  }
+}
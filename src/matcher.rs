@@ -1,14 +1,25 @@
 use anyhow::Result;
+use ignore::Match;
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use std::path::Path;
 
+/// The outcome of the matcher rules for a path, with the rule that decided it
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleMatch {
+  Include(String),
+  Exclude(String),
+  None,
+}
+
+/// Matcher rules use the .gitignore syntax, relative to the project root.
+/// A rule includes the files it matches, a rule starting with `!` excludes
+/// them. When several rules match a file, the last one wins.
 #[derive(Default, Clone)]
 pub struct Matcher(Option<MatcherKind>);
 //
 #[derive(Clone)]
 pub enum MatcherKind {
-  Exclude(Gitignore),
-
+  Rules { rules: Gitignore, includes: bool },
 }
 
 impl Matcher {
@@ -16,8 +27,18 @@ impl Matcher {
      Self(Some(matcher))
   }
 
+  /// A matcher for the rules, none if there are no rules
+  pub fn from_rules(project_dir: &Path, rules: &[String]) -> Result<Self> {
+    if rules.is_empty() { return Ok(Self::default()); }
+
+    let includes = rules.iter().any(|rule| !rule.trim().starts_with('!'));
+    let rules = Self::create_matcher(project_dir, rules.to_vec())?;
+    Ok(Self::new(MatcherKind::Rules { rules, includes }))
+  }
+
  pub fn create_matcher(project_dir: &Path, rules: Vec<String>) -> Result<Gitignore> {
 
+    // --| In .gitignore terms, includes are ignores and excludes are whitelists
     let mut builder = GitignoreBuilder::new(project_dir);
 
     for rule in rules {
@@ -26,12 +47,29 @@ impl Matcher {
     Ok(builder.build()?)
   }
 
-  pub fn should_include(&self, relative_path: &Path) -> bool {
+  /// Whether there are rules that include files. If so, files that match
+  /// no rule are excluded.
+  pub fn has_includes(&self) -> bool {
     match &self.0 {
-      Some(MatcherKind::Exclude(it)) => {
-        it.matched(relative_path, /* is_dir */ false)
-      }
+      Some(MatcherKind::Rules { includes, .. }) => *includes,
       None => false,
     }
   }
+
+  /// The last rule matching a path relative to the project root, or one of
+  /// its parent directories
+  pub fn matched(&self, relative_path: &Path) -> RuleMatch {
+    let rules = match &self.0 {
+      Some(MatcherKind::Rules { rules, .. }) => rules,
+      None => return RuleMatch::None,
+    };
+
+    if relative_path.has_root() { return RuleMatch::None; }
+
+    match rules.matched_path_or_any_parents(relative_path, /* is_dir */ false) {
+      Match::Ignore(glob) => RuleMatch::Include(glob.original().to_string()),
+      Match::Whitelist(glob) => RuleMatch::Exclude(glob.original().to_string()),
+      Match::None => RuleMatch::None,
+    }
+  }
 }
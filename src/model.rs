@@ -1,7 +1,159 @@
-use anyhow::Error;
-use crate::data_types::{Documents, EmbeddedDocuments};
+use simplelog::*;
+use tch::Device;
+use std::path::Path;
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use rust_bert::pipelines::sentence_embeddings::{
+    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType, SentenceEmbeddingsModel
+};
 
-// TODO: This is a reminder to implement additional models via traits
-trait Model {
-  fn encode(&self, documents: Documents) -> Result<EmbeddedDocuments, Error>;
+// Word pieces the all-MiniLM-*-v2 models were trained with
+const DEFAULT_MAX_SEQUENCE_LENGTH: usize = 256;
+
+// [CLS] and [SEP] are added to every input by the model
+pub const SPECIAL_TOKENS: usize = 2;
+
+// --| Model --------------------------
+// --|---------------------------------
+/// An embedding backend. New backends implement this trait and are added
+/// to `load`, which picks one using the `model.backend` setting.
+pub trait Model {
+  /// Identifier of the model, stored with the manifest and each point
+  fn name(&self) -> String;
+
+  /// Size of the produced embedding vectors
+  fn dimensions(&self) -> usize;
+
+  /// Word pieces per input, anything longer is truncated by the model
+  fn max_sequence_length(&self) -> usize;
+
+  fn encode(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Error>;
+}
+
+// --| Model Info ---------------------
+// --|---------------------------------
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelInfo {
+  pub name: String,
+  pub dimensions: usize,
+  pub max_sequence_length: usize,
+}
+
+impl ModelInfo {
+  pub fn from_model(model: &dyn Model) -> Self {
+    Self {
+      name: model.name(),
+      dimensions: model.dimensions(),
+      max_sequence_length: model.max_sequence_length(),
+    }
+  }
+}
+
+/// Create the backend selected by `model.backend` (default: rust-bert)
+pub fn load(settings: &config::Config) -> Result<Box<dyn Model>, Error> {
+  let backend = settings.get_str("model.backend").unwrap_or("rust-bert".to_string());
+
+  match backend.as_str() {
+    "rust-bert" => Ok(Box::new(RustBert::load(settings)?)),
+    _ => Err(anyhow!("Unknown model backend: {}", backend)),
+  }
+}
+
+// --| Rust Bert ----------------------
+// --|---------------------------------
+/// Sentence embeddings using rust-bert, either one of the remote
+/// all-MiniLM-*-v2 models or a local model directory
+pub struct RustBert {
+  name: String,
+  dimensions: usize,
+  max_sequence_length: usize,
+  model: SentenceEmbeddingsModel,
+}
+
+impl RustBert {
+  pub fn load(settings: &config::Config) -> Result<Self, Error> {
+    let model: SentenceEmbeddingsModel;
+    let name: String;
+    let max_sequence_length = configured_max_sequence_length(settings);
+
+    if settings.get_bool("model.local").unwrap_or(false) {
+      let path = settings.get_str("model.location")?;
+
+      debug!("Loading local model from: {}", path);
+      name = format!("rust-bert/{}", path);
+      model = SentenceEmbeddingsBuilder::local(path)
+          .with_device(Device::cuda_if_available())
+          .create_model()?;
+
+    } else {
+      debug!("Loading remote model");
+      let model_str = settings.get_str("model.location").unwrap_or("L12".to_string());
+
+      name = format!("rust-bert/all-MiniLM-{}-v2", model_str);
+      model = SentenceEmbeddingsBuilder::remote(get_model_type(&model_str))
+        .create_model()?;
+    }
+
+    // --| The dimensions are not part of the model config, so probe for them
+    let dimensions = model.encode(&["dimensions"])?
+      .first().map(|e| e.len())
+      .ok_or(anyhow!("Model returned no embedding"))?;
+
+    Ok(Self { name, dimensions, max_sequence_length, model })
+  }
+}
+
+impl Model for RustBert {
+  fn name(&self) -> String { self.name.clone() }
+
+  fn dimensions(&self) -> usize { self.dimensions }
+
+  fn max_sequence_length(&self) -> usize { self.max_sequence_length }
+
+  fn encode(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+    Ok(self.model.encode(texts)?)
+  }
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+fn get_model_type(model_str: &str) -> SentenceEmbeddingsModelType {
+  let model_type: SentenceEmbeddingsModelType;
+
+  match model_str {
+    "L6" => model_type = SentenceEmbeddingsModelType::AllMiniLmL6V2,
+    "L12" => model_type = SentenceEmbeddingsModelType::AllMiniLmL12V2,
+    _ => model_type = SentenceEmbeddingsModelType::AllMiniLmL12V2
+  }
+  model_type
+}
+
+/// Word pieces the configured model accepts. Known without loading the
+/// model, so the indexer can use it too. `model.max_sequence_length` can
+/// only lower the limit of the model, rust-bert truncates at its own limit.
+pub fn configured_max_sequence_length(settings: &config::Config) -> usize {
+  let limit = model_max_sequence_length(settings);
+
+  match settings.get_int("model.max_sequence_length") {
+    Ok(length) if length > SPECIAL_TOKENS as i64 => (length as usize).min(limit),
+    _ => limit,
+  }
+}
+
+/// The limit of the local model, or the default of the remote models
+pub fn model_max_sequence_length(settings: &config::Config) -> usize {
+  if settings.get_bool("model.local").unwrap_or(false) {
+    if let Ok(path) = settings.get_str("model.location") {
+      if let Some(length) = read_max_sequence_length(Path::new(&path)) { return length; }
+    }
+  }
+
+  DEFAULT_MAX_SEQUENCE_LENGTH
+}
+
+/// sentence-transformers models keep the limit in sentence_bert_config.json
+fn read_max_sequence_length(path: &Path) -> Option<usize> {
+  let content = std::fs::read_to_string(path.join("sentence_bert_config.json")).ok()?;
+  let config: serde_json::Value = serde_json::from_str(&content).ok()?;
+  config.get("max_seq_length")?.as_u64().map(|length| length as usize)
 }
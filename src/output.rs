@@ -0,0 +1,56 @@
+use serde::Serialize;
+use std::str::FromStr;
+use anyhow::{anyhow, Error};
+
+use crate::SETTINGS;
+
+// --| Output Format ------------------
+// --|---------------------------------
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat { Text, Json, Jsonl }
+
+impl FromStr for OutputFormat {
+  type Err = Error;
+
+  fn from_str(format: &str) -> Result<Self, Self::Err> {
+    match format {
+      "text" => Ok(OutputFormat::Text),
+      "json" => Ok(OutputFormat::Json),
+      "jsonl" => Ok(OutputFormat::Jsonl),
+      _ => Err(anyhow!("Unknown output format: {}", format)),
+    }
+  }
+}
+
+impl OutputFormat {
+  /// The format set through `--output`, text by default
+  pub fn from_settings() -> Self {
+    SETTINGS.read().unwrap().get_str("output.format").ok()
+      .and_then(|format| format.parse().ok())
+      .unwrap_or(OutputFormat::Text)
+  }
+
+  pub fn is_text(&self) -> bool { *self == OutputFormat::Text }
+}
+
+// --| Print Output -------------------
+// --|---------------------------------
+/// Print a list as a json array, as one json object per line, or with `text`
+pub fn print_list<T: Serialize>(format: OutputFormat, items: &[T], text: impl FnOnce(&[T])) {
+  match format {
+    OutputFormat::Json => println!("{}", serde_json::to_string_pretty(items).unwrap()),
+    OutputFormat::Jsonl => {
+      for item in items { println!("{}", serde_json::to_string(item).unwrap()); }
+    },
+    OutputFormat::Text => text(items),
+  }
+}
+
+/// Print a single value as json, as a single json line, or with `text`
+pub fn print_item<T: Serialize>(format: OutputFormat, item: &T, text: impl FnOnce(&T)) {
+  match format {
+    OutputFormat::Json => println!("{}", serde_json::to_string_pretty(item).unwrap()),
+    OutputFormat::Jsonl => println!("{}", serde_json::to_string(item).unwrap()),
+    OutputFormat::Text => text(item),
+  }
+}
@@ -1,51 +1,52 @@
 use uuid;
 use anyhow::Result;
 use std::collections::HashMap;
-use chrono::{Local, DateTime};
+use serde_json::Value as JsonValue;
+use async_trait::async_trait;
 use qdrant_client::prelude::*;
+use qdrant_client::qdrant::value::Kind;
+use qdrant_client::qdrant::r#match::MatchValue;
+use qdrant_client::qdrant::point_id::PointIdOptions;
+use qdrant_client::qdrant::condition::ConditionOneOf;
 use qdrant_client::qdrant::vectors_config::Config as vConfig;
 use qdrant_client::qdrant::with_payload_selector::SelectorOptions;
-use qdrant_client::qdrant::{CreateCollection, SearchPoints, VectorParams, VectorsConfig, Vectors, SearchResponse, Filter, WithPayloadSelector, SearchParams, WithVectorsSelector, ReadConsistency };
+use qdrant_client::qdrant::points_selector::PointsSelectorOneOf;
+use qdrant_client::qdrant::{CreateCollection, SearchPoints, ScrollPoints, VectorParams, VectorsConfig, Vectors, Filter, WithPayloadSelector, PointsSelector, PointsIdsList, PointId, Condition, FieldCondition, Match, ListValue, Struct };
 
 use crate::data_types::EmbeddedDocuments;
-use crate::vectorize::text_embedding_async;
+use crate::database::{Database, SearchQuery, SearchHit, StoredPoint, CollectionInfo, PayloadFilter, fragment_payload};
 
-// --| Qdrant DataTypes ---------------
-// --|---------------------------------
-#[derive(Debug, Clone, Default)]
-pub struct SearchData{
-  pub search_term: String,
-  pub collection: Option<String>,
-  pub vector: Option< Vec<f32>>,
-  pub filter: Option<Filter>,
-  pub limit: Option<u64>,
-  pub with_payload: Option<WithPayloadSelector>,
-  pub params: Option<SearchParams>,
-  pub score_threshold: Option<f32>,
-  pub offset: Option<u64>,
-  pub vector_name: Option<String>,
-  pub with_vectors: Option<WithVectorsSelector>,
-  pub read_consistency: Option<ReadConsistency>,
-}
+// Points fetched per scroll request
+const SCROLL_PAGE_SIZE: u32 = 256;
 
-// --| Qdrant Functions ---------------
+// --| Qdrant Database ----------------
 // --|---------------------------------
-pub async fn add_documents(client: QdrantClient, documents: EmbeddedDocuments) -> Result<()> {
-  let mut collection_name = documents.collection.clone();
+pub struct QdrantDatabase {
+  client: QdrantClient,
+}
 
-  if collection_name.is_empty() {
-    collection_name = "test_collection".to_string();
+impl QdrantDatabase {
+  pub async fn connect(url: &str) -> Result<Self> {
+    let config = QdrantClientConfig::from_url(url);
+    let client = QdrantClient::new(Some(config)).await?;
+    Ok(Self { client })
   }
+}
 
-  let result = client.has_collection(&collection_name).await?;
+#[async_trait]
+impl Database for QdrantDatabase {
+  async fn list_collections(&self) -> Result<Vec<String>> {
+    let collections = self.client.list_collections().await?;
+    Ok(collections.collections.into_iter().map(|c| c.name).collect())
+  }
 
-  if !result {
-    client
+  async fn create_collection(&self, collection: &str, dimensions: usize) -> Result<()> {
+    self.client
       .create_collection(&CreateCollection {
-        collection_name: collection_name.clone().into(),
+        collection_name: collection.into(),
         vectors_config: Some(VectorsConfig {
           config: Some(vConfig::Params(VectorParams {
-            size: 384,
+            size: dimensions as u64,
             distance: Distance::Cosine.into(),
             hnsw_config: None,
             quantization_config: None,
@@ -54,131 +55,193 @@ pub async fn add_documents(client: QdrantClient, documents: EmbeddedDocuments) -
         ..Default::default()
       })
     .await?;
+
+    Ok(())
+  }
+
+  async fn collection_info(&self, collection: &str) -> Result<Option<CollectionInfo>> {
+    if !self.client.has_collection(collection).await? { return Ok(None); }
+
+    let info = self.client.collection_info(collection).await?;
+    let result = match info.result {
+      Some(result) => result,
+      None => return Ok(None),
+    };
+
+    let dimensions = result.config
+      .and_then(|config| config.params)
+      .and_then(|params| params.vectors_config)
+      .and_then(|vectors| vectors.config)
+      .map(|config| match config {
+        vConfig::Params(params) => params.size,
+        _ => 0,
+      })
+      .unwrap_or(0);
+
+    Ok(Some(CollectionInfo {
+      name: collection.to_string(),
+      points: result.points_count,
+      dimensions,
+    }))
+  }
+
+  async fn drop_collection(&self, collection: &str) -> Result<()> {
+    self.client.delete_collection(collection).await?;
+    Ok(())
+  }
+
+  async fn upsert(&self, documents: EmbeddedDocuments) -> Result<()> {
+    let mut point_vec: Vec<PointStruct> = vec![];
+
+    for document in &documents.documents {
+      let mut payload = Payload::new();
+      for (key, value) in fragment_payload(document, &documents.model) {
+        payload.insert(key, from_json(value));
+      }
+
+      let point_struct = PointStruct{
+        id: Some(point_id(&document.id).into()),
+        payload: payload.into(),
+        vectors: Some(Vectors::from(document.embeddings.clone().to_vec())),
+      };
+
+      point_vec.push(point_struct);
+    }
+
+    self.client.upsert_points_blocking(documents.collection, point_vec, None).await?;
+    Ok(())
   }
-  
-  let mut point_vec: Vec<PointStruct> = vec![];
-
-  let now: DateTime<Local> = Local::now();
-
-  for document in documents.documents {
-    let id: String = document.id;
-    let document_id = document.document_id;
-    let name = document.name;
-    let text = document.text;
-    let metadata = document.metadata;
-
-    // let mut meta_vec: Vec<(String, String)> = vec![];
-    // for (key, value) in metadata {
-    //   meta_vec.push((key, value.to_string()));
-    // }
-
-    let meta = serde_json::to_string(&metadata).unwrap().to_string(); 
-
-    let tmp_payload  = vec![
-      ("id", id.clone().into()),
-      ("document_id", document_id.into()),
-      ("name", name.into()),
-      ("text", text.into()),
-      ("created_at", now.clone().to_string().into()),
-      ("metadata", meta.into())
-    ];
-
-    let payload: Payload = tmp_payload.into_iter().collect::<HashMap<_, Value>>().into();
-
-    let point_struct = PointStruct{
-      id: Some(uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, id.as_bytes()).to_string().into()),
-      payload: payload.into(),
-      vectors: Some(Vectors::from(document.embeddings.clone().to_vec())), 
+
+  async fn delete(&self, collection: &str, fragment_ids: &[String]) -> Result<()> {
+    let ids = fragment_ids.iter().map(|id| point_id(id).into()).collect::<Vec<PointId>>();
+    let selector = PointsSelector {
+      points_selector_one_of: Some(PointsSelectorOneOf::Points(PointsIdsList { ids })),
     };
 
-    point_vec.push(point_struct);
+    self.client.delete_points_blocking(collection, &selector, None).await?;
+    Ok(())
   }
 
-  client.upsert_points_blocking(collection_name, point_vec, None).await?;
+  async fn search(&self, query: SearchQuery) -> Result<Vec<SearchHit>> {
+    let search_points = SearchPoints {
+      collection_name: query.collection,
+      limit: query.limit,
+      vector: query.vector,
+      offset: query.offset,
+      score_threshold: query.score_threshold,
+      filter: query.filter.map(to_filter),
+      with_payload: Some(WithPayloadSelector {
+        selector_options: Some(SelectorOptions::Enable(true)),
+      }),
+      ..Default::default()
+    };
+
+    let results = self.client.search_points(&search_points).await?;
 
-  Ok(())
+    Ok(results.result.into_iter().map(|point| {
+      let payload = to_json_map(point.payload);
+      SearchHit { id: fragment_id(point.id, &payload), score: point.score, payload }
+    }).collect())
+  }
+
+  async fn scroll(&self, collection: &str, filter: Option<PayloadFilter>) -> Result<Vec<StoredPoint>> {
+    let mut points = vec![];
+    let mut offset: Option<PointId> = None;
+
+    loop {
+      let response = self.client.scroll(&ScrollPoints {
+        collection_name: collection.to_string(),
+        filter: filter.clone().map(to_filter),
+        offset: offset.clone(),
+        limit: Some(SCROLL_PAGE_SIZE),
+        with_payload: Some(WithPayloadSelector {
+          selector_options: Some(SelectorOptions::Enable(true)),
+        }),
+        ..Default::default()
+      }).await?;
+
+      for point in response.result {
+        let payload = to_json_map(point.payload);
+        points.push(StoredPoint { id: fragment_id(point.id, &payload), payload });
+      }
+
+      match response.next_page_offset {
+        Some(next) => offset = Some(next),
+        None => break,
+      }
+    }
+
+    Ok(points)
+  }
 }
 
-// --| Search Database ----------------
+// --| Helper functions ---------------
 // --|---------------------------------
-pub async fn search_documents(client: QdrantClient, search: SearchData) -> Result<SearchResponse> {
-  let search = search.search_term;
-  let limit = 4 * 13 as u64;
-
-  let search_vector = text_embedding_async(search.clone()).await; 
-
-  let search_points = SearchPoints {
-    collection_name: "test_collection".into(),
-    limit,
-    vector: search_vector,
-    with_payload: Some(WithPayloadSelector {
-      selector_options: Some(SelectorOptions::Enable(true)),
-    }),
-    ..Default::default() 
-  };
+/// Qdrant point ids must be integers or UUIDs, so fragment ids are hashed
+pub fn point_id(fragment_id: &str) -> String {
+  uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_OID, fragment_id.as_bytes()).to_string()
+}
 
-  let results = client.search_points(&search_points).await?;
+/// The fragment id is kept in the payload, the point id is only a fallback
+fn fragment_id(id: Option<PointId>, payload: &HashMap<String, JsonValue>) -> String {
+  if let Some(JsonValue::String(id)) = payload.get("id") { return id.clone(); }
 
-  dbg!(&results);
-  Ok(results)
+  match id.and_then(|id| id.point_id_options) {
+    Some(PointIdOptions::Num(num)) => num.to_string(),
+    Some(PointIdOptions::Uuid(uuid)) => uuid,
+    None => String::new(),
+  }
 }
 
-// --| Test Connectivity --------------
-// --|---------------------------------
-pub async fn test_connection(client: QdrantClient) -> Result<()> {
-  let collections_list = client.list_collections().await?;
-
-  dbg!(collections_list);
-
-  let collection_name = "test";
-  client.delete_collection(collection_name).await?;
-
-  client
-    .create_collection(
-      &CreateCollection {
-        collection_name: collection_name.into(),
-        vectors_config: Some(
-          VectorsConfig {
-            config: Some(vConfig::Params(VectorParams {
-              size: 10,
-              distance: Distance::Cosine.into(),
-              hnsw_config: None,
-              quantization_config: None,
-            })),
-          }),
-          ..Default::default()
-      }).await?;
+fn to_filter(filter: PayloadFilter) -> Filter {
+  let must = filter.into_iter().filter_map(|(key, value)| {
+    let match_value = match value {
+      JsonValue::String(s) => MatchValue::Keyword(s),
+      JsonValue::Bool(b) => MatchValue::Boolean(b),
+      JsonValue::Number(n) => MatchValue::Integer(n.as_i64()?),
+      _ => return None,
+    };
 
-  let collection_info = client.collection_info(collection_name).await?;
-  dbg!(collection_info);
-
-  let payload: Payload = vec![
-    ("foo", "Bar".into()),
-    ("bar", 12.into())
-  ].into_iter().collect::<HashMap<_, Value>>().into();
-
-  let points = vec![
-    PointStruct::new(0, vec![12.; 10], payload)
-  ];
- 
-  client.upsert_points_blocking(collection_name, points, None).await?;
-
-  let search_result = client
-    .search_points(
-      &SearchPoints {
-        collection_name: collection_name.into(),
-        vector: vec![11.; 10],
-        filter: None,
-        limit: 10,
-        with_vectors: None,
-        with_payload: None,
-        params: None,
-        score_threshold: None,
-        offset: None,
+    Some(Condition {
+      condition_one_of: Some(ConditionOneOf::Field(FieldCondition {
+        key,
+        r#match: Some(Match { match_value: Some(match_value) }),
         ..Default::default()
-    }).await?;
+      })),
+    })
+  }).collect();
 
-  dbg!(search_result);
+  Filter { must, ..Default::default() }
+}
+
+fn to_json_map(payload: HashMap<String, Value>) -> HashMap<String, JsonValue> {
+  payload.into_iter().map(|(key, value)| (key, to_json(value))).collect()
+}
+
+fn to_json(value: Value) -> JsonValue {
+  match value.kind {
+    None | Some(Kind::NullValue(_)) => JsonValue::Null,
+    Some(Kind::BoolValue(b)) => JsonValue::Bool(b),
+    Some(Kind::IntegerValue(i)) => JsonValue::from(i),
+    Some(Kind::DoubleValue(d)) => JsonValue::from(d),
+    Some(Kind::StringValue(s)) => JsonValue::String(s),
+    Some(Kind::ListValue(list)) => JsonValue::Array(list.values.into_iter().map(to_json).collect()),
+    Some(Kind::StructValue(st)) => JsonValue::Object(st.fields.into_iter().map(|(k, v)| (k, to_json(v))).collect()),
+  }
+}
+
+fn from_json(value: JsonValue) -> Value {
+  let kind = match value {
+    JsonValue::Null => Kind::NullValue(0),
+    JsonValue::Bool(b) => Kind::BoolValue(b),
+    JsonValue::Number(n) => match n.as_i64() {
+      Some(i) => Kind::IntegerValue(i),
+      None => Kind::DoubleValue(n.as_f64().unwrap_or_default()),
+    },
+    JsonValue::String(s) => Kind::StringValue(s),
+    JsonValue::Array(values) => Kind::ListValue(ListValue { values: values.into_iter().map(from_json).collect() }),
+    JsonValue::Object(fields) => Kind::StructValue(Struct { fields: fields.into_iter().map(|(k, v)| (k, from_json(v))).collect() }),
+  };
 
-  Ok(())
+  Value { kind: Some(kind) }
 }
@@ -0,0 +1,127 @@
+use simplelog::*;
+use serde::Serialize;
+use serde_json::Value;
+use anyhow::{anyhow, Error};
+use std::collections::HashMap;
+
+use crate::vectorize::Model;
+use crate::database::{Database, SearchQuery, SearchHit, collection_name};
+
+// Characters of fragment text shown per result
+const SNIPPET_LENGTH: usize = 160;
+
+// --| Search Options -----------------
+// --|---------------------------------
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+  pub term: String,
+  pub collection: String,
+  pub limit: u64,
+  pub offset: Option<u64>,
+  pub score_threshold: Option<f32>,
+}
+
+// --| Search Result ------------------
+// --|---------------------------------
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+  pub rank: usize,
+  pub score: f32,
+  pub id: String,
+  pub path: String,
+  pub fragment: Option<usize>,
+  pub start_line: Option<u64>,
+  pub end_line: Option<u64>,
+  pub text: String,
+  pub payload: HashMap<String, Value>,
+}
+
+impl SearchResult {
+  pub fn from_hit(rank: usize, hit: SearchHit) -> Self {
+    // --| File metadata is stored as a json string
+    let metadata: HashMap<String, Value> = hit.payload.get("metadata")
+      .and_then(|m| m.as_str())
+      .and_then(|m| serde_json::from_str(m).ok())
+      .unwrap_or_default();
+
+    let path = ["relative_path", "path"].iter()
+      .find_map(|key| metadata.get(*key).and_then(|p| p.as_str()))
+      .or(hit.payload.get("name").and_then(|n| n.as_str()))
+      .unwrap_or_default()
+      .to_string();
+
+    let fragment = hit.id.rsplit_once('_').and_then(|(_, index)| index.parse::<usize>().ok());
+    let text = hit.payload.get("text").and_then(|t| t.as_str()).unwrap_or_default().to_string();
+
+    // --| Points uploaded before line ranges were stored have none
+    let start_line = hit.payload.get("start_line").and_then(|l| l.as_u64());
+    let end_line = hit.payload.get("end_line").and_then(|l| l.as_u64());
+
+    Self { rank, score: hit.score, id: hit.id, path, fragment, start_line, end_line, text, payload: hit.payload }
+  }
+
+  /// The start of the text on a single line
+  pub fn snippet(&self) -> String {
+    let text = self.text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if text.chars().count() <= SNIPPET_LENGTH { return text; }
+
+    format!("{}...", text.chars().take(SNIPPET_LENGTH).collect::<String>())
+  }
+}
+
+// --| Search -------------------------
+// --|---------------------------------
+/// Embed the search term with the configured model and query the collection
+pub async fn search(database: &dyn Database, model: &Model, options: SearchOptions) -> Result<Vec<SearchResult>, Error> {
+  let model_info = model.info().await?;
+  let vector = model.embed(vec![options.term.clone()]).await?.remove(0);
+
+  let collection = collection_name(&options.collection);
+  match database.collection_info(&collection).await? {
+    Some(info) => if info.dimensions != model_info.dimensions as u64 {
+      error!("Collection has {} dimensions, but {} produces {}", info.dimensions, model_info.name, model_info.dimensions);
+      return Err(anyhow!("Model does not match the collection"));
+    },
+    None => {
+      error!("Collection {} does not exist", collection);
+      return Err(anyhow!("Collection {} does not exist", collection));
+    }
+  }
+
+  info!("Searching {} for {}", collection, options.term);
+  let search_query = SearchQuery {
+    collection,
+    vector,
+    limit: options.limit,
+    offset: options.offset,
+    score_threshold: options.score_threshold,
+    ..Default::default()
+  };
+
+  let hits = database.search(search_query).await?;
+
+  if let Some(uploaded) = hits.first().and_then(|hit| hit.payload.get("model")).and_then(|m| m.as_str()) {
+    if uploaded != model_info.name {
+      warn!("Collection was uploaded with {}, but searched with {}", uploaded, model_info.name);
+    }
+  }
+
+  let offset = options.offset.unwrap_or(0) as usize;
+  Ok(hits.into_iter().enumerate().map(|(i, hit)| SearchResult::from_hit(offset + i + 1, hit)).collect())
+}
+
+// --| Print Results ------------------
+// --|---------------------------------
+pub fn print_results(results: &[SearchResult]) {
+  if results.is_empty() {
+    println!("No results");
+    return;
+  }
+
+  for result in results {
+    let fragment = result.fragment.map(|f| format!(" #{}", f)).unwrap_or_default();
+    let line = result.start_line.map(|l| format!(":{}", l)).unwrap_or_default();
+    println!("{:>3}. {:.4}  {}{}{}", result.rank, result.score, result.path, line, fragment);
+    println!("     {}", result.snippet());
+  }
+}
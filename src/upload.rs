@@ -0,0 +1,286 @@
+use simplelog::*;
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+use crate::perf;
+use crate::indexer::{self, document_id, project_namespace};
+use crate::SETTINGS;
+use crate::vectorize::Model;
+use crate::manifest::Manifest;
+use crate::data_types::{Documents, EmbeddedDocuments, FileError};
+use crate::database::{Database, PayloadFilter, add_documents, delete_fragments, collection_name};
+
+// Points per upsert request, a failed request only fails the files within it
+const UPLOAD_BATCH_SIZE: usize = 256;
+
+// --| Upload DataTypes ---------------
+// --|---------------------------------
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+  pub force: bool,
+  pub prune: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadFailure {
+  pub path: String,
+  pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadSummary {
+  pub collection: String,
+  pub model: String,
+  pub uploaded_files: Vec<String>,
+  pub uploaded_fragments: usize,
+  pub unchanged_files: usize,
+  pub skipped_files: Vec<String>,
+  pub missing_files: Vec<String>,
+  pub removed_files: Vec<String>,
+  pub removed_fragments: usize,
+  pub failures: Vec<UploadFailure>,
+  pub errors: Vec<FileError>,
+}
+
+// --| Upload -------------------------
+// --|---------------------------------
+/// Index the project and upload every changed file, then remove stale points
+pub async fn upload(database: &dyn Database, options: UploadOptions) -> Result<UploadSummary, Error> {
+  upload_with(database, None, options).await
+}
+
+/// Upload with a model that is already loaded, or one loaded on demand
+pub async fn upload_with(database: &dyn Database, model: Option<&Model>, options: UploadOptions) -> Result<UploadSummary, Error> {
+  // --| Unchanged files are skipped unless forced
+  let mut manifest = Manifest::load(&SETTINGS.read().unwrap());
+  let is_file = SETTINGS.read().unwrap().get_bool("indexer.is_file").unwrap_or(false);
+
+  let index_start = Instant::now();
+  let documents = match options.force {
+    true => indexer::build_index(None)?,
+    false => indexer::build_index(Some(&manifest))?,
+  };
+  perf!("Indexing time: {:?}", index_start.elapsed());
+
+  // --| Files a git diff removed are always deleted
+  let missing = manifest.missing_files(&documents, is_file);
+  let options = UploadOptions { prune: options.prune || documents.partial, ..options };
+  upload_documents(database, model, &mut manifest, documents, missing, &options).await
+}
+
+// --| Upload Documents ---------------
+// --|---------------------------------
+/// Embed and upload indexed documents, then remove their stale fragments
+/// and, with `prune`, the points of the missing files. The manifest is
+/// updated and saved. A model is only loaded when there is something to
+/// embed and none was given.
+pub async fn upload_documents(
+  database: &dyn Database,
+  model: Option<&Model>,
+  manifest: &mut Manifest,
+  documents: Documents,
+  missing: Vec<String>,
+  options: &UploadOptions,
+) -> Result<UploadSummary, Error> {
+  let upload_start = Instant::now();
+  let mut summary = UploadSummary {
+    collection: collection_name(&documents.collection),
+    unchanged_files: documents.unchanged.len(),
+    skipped_files: documents.skipped.clone(),
+    errors: documents.errors.clone(),
+    ..Default::default()
+  };
+
+  // --| Work out stale points before the manifest is updated
+  let mut report = manifest.plan_prune(&documents, &missing, options.prune);
+
+  // --| A partial run, such as one in CI, may have no manifest to go by
+  let mut stored = HashMap::new();
+  if documents.partial && options.prune {
+    stored = stored_stale_fragments(database, &summary.collection, &documents, &missing).await?;
+    report.removed_fragments.extend(stored.values().flatten().cloned());
+    report.removed_fragments.sort();
+    report.removed_fragments.dedup();
+
+    for path in &missing {
+      if !report.removed_files.contains(path) { report.removed_files.push(path.clone()); }
+    }
+  }
+
+  if !options.prune && !missing.is_empty() {
+    warn!("{} files no longer exist in the project, run with --prune to remove them", missing.len());
+    for path in &missing { info!("Missing: {}", path); }
+    summary.missing_files = missing;
+  }
+
+  if documents.documents.is_empty() && report.is_empty() {
+    if !documents.unchanged.is_empty() {
+      info!("No changes since the last upload");
+    } else {
+      warn!("No documents found");
+    }
+    return Ok(summary);
+  }
+
+  let entries = Manifest::entries(&documents);
+
+  if !documents.documents.is_empty() {
+    let spawned: Model;
+    let model = match model {
+      Some(model) => model,
+      None => {
+        spawned = Model::load();
+        &spawned
+      }
+    };
+
+    let embed_start = Instant::now();
+    let doc_embeds = model.encode(documents).await?;
+    perf!("Embedding time: {:?}", embed_start.elapsed());
+    debug!("{:?}", &doc_embeds);
+
+    summary.model = doc_embeds.model.clone();
+
+    // --| Only files that were uploaded are recorded in the manifest
+    let add_start = Instant::now();
+    for batch in split_batches(doc_embeds) {
+      let mut document_ids = batch.documents.iter().map(|d| d.document_id.clone()).collect::<Vec<_>>();
+      document_ids.dedup();
+      let fragments = batch.documents.len();
+
+      let result = add_documents(database, batch).await;
+      for document_id in document_ids {
+        let (path, entry) = match entries.get(&document_id) {
+          Some(entry) => entry.clone(),
+          None => continue,
+        };
+
+        match &result {
+          Ok(_) => {
+            summary.uploaded_files.push(path.clone());
+            manifest.files.insert(path, entry);
+          },
+          Err(err) => {
+            error!("Failed to upload {}: {}", path, err);
+            summary.failures.push(UploadFailure { path, error: err.to_string() });
+          },
+        }
+      }
+
+      if result.is_ok() { summary.uploaded_fragments += fragments; }
+    }
+    perf!("Upload time: {:?}", add_start.elapsed());
+  }
+
+  // --| Files that failed to upload keep their old points
+  for failure in &summary.failures {
+    if let Some(entry) = manifest.files.get(&failure.path) { report.keep_fragments(&entry.fragments); }
+    if let Some(fragments) = stored.get(&failure.path) { report.keep_fragments(fragments); }
+  }
+
+  // --| Remove stale points ---
+  if !report.is_empty() {
+    let prune_start = Instant::now();
+    delete_fragments(database, &summary.collection, &report.removed_fragments).await?;
+    manifest.apply_prune(&report);
+    perf!("Prune time: {:?}", prune_start.elapsed());
+
+    for path in &report.removed_files { info!("Removed: {}", path); }
+    info!("Removed {} stale fragments, {} deleted files",
+      report.removed_fragments.len(), report.removed_files.len());
+
+    summary.removed_files = report.removed_files;
+    summary.removed_fragments = report.removed_fragments.len();
+  }
+
+  database.flush().await?;
+  manifest.save()?;
+
+  perf!("Processing time: {:?}", upload_start.elapsed());
+  Ok(summary)
+}
+
+// --| Print Summary ------------------
+// --|---------------------------------
+pub fn print_summary(summary: &UploadSummary) {
+  println!("Uploaded {} files ({} fragments) to {}, {} unchanged",
+    summary.uploaded_files.len(), summary.uploaded_fragments, summary.collection, summary.unchanged_files);
+
+  if !summary.removed_files.is_empty() || summary.removed_fragments > 0 {
+    println!("Removed {} stale fragments, {} deleted files", summary.removed_fragments, summary.removed_files.len());
+  }
+
+  if !summary.skipped_files.is_empty() {
+    println!("Skipped {} binary files", summary.skipped_files.len());
+  }
+
+  if !summary.missing_files.is_empty() {
+    println!("{} files no longer exist in the project, run with --prune to remove them", summary.missing_files.len());
+  }
+
+  for error in &summary.errors {
+    println!("Could not index: {}: {}", error.path, error.error);
+  }
+
+  for failure in &summary.failures {
+    println!("Failed: {}: {}", failure.path, failure.error);
+  }
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+/// Stale points found in the database by document id, per path: fragments
+/// of the documents past their new end, and every fragment of the missing
+/// files
+async fn stored_stale_fragments(database: &dyn Database, collection: &str, documents: &Documents, missing: &[String]) -> Result<HashMap<String, Vec<String>>, Error> {
+  if database.collection_info(collection).await?.is_none() { return Ok(HashMap::new()); }
+
+  let namespace = project_namespace(&SETTINGS.read().unwrap());
+  let filter = |id: String| PayloadFilter::from([("document_id".to_string(), Value::from(id))]);
+  let mut stale: HashMap<String, Vec<String>> = HashMap::new();
+
+  for document in &documents.documents {
+    let current = document.fragments.iter().map(|f| f.id.clone()).collect::<HashSet<String>>();
+    let points = database.scroll(collection, Some(filter(document.id.clone()))).await?;
+    stale.entry(document.path.clone()).or_default()
+      .extend(points.into_iter().map(|point| point.id).filter(|id| !current.contains(id)));
+  }
+
+  for path in missing {
+    let points = database.scroll(collection, Some(filter(document_id(&namespace, path)))).await?;
+    stale.entry(path.clone()).or_default().extend(points.into_iter().map(|point| point.id));
+  }
+
+  Ok(stale)
+}
+
+/// Split into batches of about UPLOAD_BATCH_SIZE points, without splitting
+/// the fragments of a document across batches
+fn split_batches(documents: EmbeddedDocuments) -> Vec<EmbeddedDocuments> {
+  let mut batches: Vec<EmbeddedDocuments> = vec![];
+  let mut current = empty_batch(&documents);
+
+  for document in documents.documents.iter() {
+    let new_document = current.documents.last().map(|d| d.document_id != document.document_id).unwrap_or(false);
+    if new_document && current.documents.len() >= UPLOAD_BATCH_SIZE {
+      batches.push(current);
+      current = empty_batch(&documents);
+    }
+    current.documents.push(document.clone());
+  }
+
+  if !current.documents.is_empty() { batches.push(current); }
+  batches
+}
+
+fn empty_batch(documents: &EmbeddedDocuments) -> EmbeddedDocuments {
+  let mut batch = EmbeddedDocuments::new();
+  batch.collection = documents.collection.clone();
+  batch.metadata = documents.metadata.clone();
+  batch.model = documents.model.clone();
+  batch.dimensions = documents.dimensions;
+  batch
+}
@@ -1,141 +1,208 @@
 use simplelog::*;
 use anyhow::Error;
-use tch::Device;
 use std::time::Instant;
 use tokio::{sync::oneshot, task};
+use std::path::PathBuf;
 use std::{ sync::mpsc, thread::{self, JoinHandle} };
 
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType, SentenceEmbeddingsModel
-};
-
+use crate::perf;
+use crate::daemon;
+use crate::model::{self, ModelInfo};
 use crate::SETTINGS;
 use crate::data_types::{Documents, EmbeddedDocuments};
 
 // --| Model Setup ------------------------------
 // --|-------------------------------------------
-type Message = (Documents, oneshot::Sender<EmbeddedDocuments>);
+enum Message {
+  Documents(Documents, oneshot::Sender<Result<EmbeddedDocuments, Error>>),
+  Texts(Vec<String>, oneshot::Sender<Result<Vec<Vec<f32>>, Error>>),
+  Info(oneshot::Sender<ModelInfo>),
+}
+
+// Fragments embedded per call to the model, override with model.batch_size
+const DEFAULT_BATCH_SIZE: usize = 32;
 
+/// Handle to the configured model backend, which runs on its own thread
+/// or in a `serve-model` daemon. Both upload and search go through it so
+/// they always use the same model.
 pub struct Model {
-  sender: mpsc::SyncSender<Message>,
+  runner: Runner,
 }
 
-fn get_model_type(model_str: &str) -> SentenceEmbeddingsModelType {
-  let model_type: SentenceEmbeddingsModelType; 
-
-  match model_str {
-    "L6" => model_type = SentenceEmbeddingsModelType::AllMiniLmL6V2,
-    "L12" => model_type = SentenceEmbeddingsModelType::AllMiniLmL12V2,
-    _ => model_type = SentenceEmbeddingsModelType::AllMiniLmL12V2
-  }
-  model_type
+enum Runner {
+  Thread(mpsc::SyncSender<Message>),
+  Daemon(PathBuf),
 }
 
 impl Model {
+  /// The daemon serving the configured model if one is running, otherwise
+  /// the model is loaded in process
+  pub fn load() -> Model {
+    let socket = daemon::running(&SETTINGS.read().unwrap());
+    match socket {
+      Some(socket) => {
+        info!("Using the model daemon at {}", socket.display());
+        Model { runner: Runner::Daemon(socket) }
+      },
+      None => Self::spawn().1,
+    }
+  }
+
+  /// Load the model on its own thread
   pub fn spawn() -> (JoinHandle<anyhow::Result<()>>, Model) {
     let (sender, receiver) = mpsc::sync_channel(100);
     let handle = thread::spawn(move || Self::runner(receiver));
-    (handle, Model { sender })
+    (handle, Model { runner: Runner::Thread(sender) })
   }
 
   fn runner(receiver: mpsc::Receiver<Message>) -> anyhow::Result<(), Error> {
     debug!("Starting model runner");
 
-    let settings = SETTINGS.read().unwrap();
-    let model: SentenceEmbeddingsModel;
-
-    if settings.get_bool("model.local").unwrap() {
-      let path = settings.get_str("model.location")?;
-      
-      debug!("Loading local model from: {}", path);
-      model = SentenceEmbeddingsBuilder::local(path)
-          .with_device(Device::cuda_if_available())
-          .create_model()?;
-
-    } else {
-      debug!("Loading remote model");
-      let model_str = settings.get_str("model.location")?;
-      
-      model = SentenceEmbeddingsBuilder::remote(get_model_type(&model_str))
-        .create_model()
-        .expect("Could not load model");
-    }
-    
-    while let Ok((documents, sender)) = receiver.recv() {
-      let mut embedded_documents = Vec::new();
-      let mut document_average_time = vec![];
-
-      let documents_start = Instant::now();
-      for document in &documents.documents {
-        for fragment in &document.fragments {
-          let doc_start = Instant::now();
+    let model: Box<dyn model::Model>;
+    let batch_size: usize;
 
-          let embedding = model.encode(&[fragment.text.clone()]).expect("Could not embed fragment");
-          embedded_documents.push(fragment.to_embedded(embedding[0].clone()));
-
-          document_average_time.push(doc_start.elapsed());
-        }
+    // --| Settings lock is released before embedding, perf! needs it
+    {
+      let settings = SETTINGS.read().unwrap();
+      batch_size = settings.get_int("model.batch_size").map(|b| b.max(1) as usize).unwrap_or(DEFAULT_BATCH_SIZE);
+      model = model::load(&settings)?;
+    }
+    debug!("Loaded {}: {} dimensions, {} max sequence length", model.name(), model.dimensions(), model.max_sequence_length());
+
+    // --| Errors go back to the caller, the runner keeps serving. A caller
+    // --| that went away does not want the result anymore.
+    while let Ok(message) = receiver.recv() {
+      match message {
+        Message::Documents(documents, sender) => {
+          let embedded = embed_documents(model.as_ref(), batch_size, documents);
+          let _ = sender.send(embedded);
+        },
+
+        Message::Texts(texts, sender) => {
+          let embeddings = embed_batched(model.as_ref(), batch_size, &texts);
+          let _ = sender.send(embeddings);
+        },
+
+        Message::Info(sender) => {
+          let _ = sender.send(ModelInfo::from_model(model.as_ref()));
+        },
       }
-      info!("Documents embedded in {:?}", documents_start.elapsed());
+    }
 
-      let mut total_time = 0;
-      for time in &document_average_time {
-        total_time += time.as_millis();
-      }
+    Ok(())
+  }
 
-      let total_items = &document_average_time.len();
+  /// Embed the fragments of the documents, the daemon only gets their texts
+  pub async fn encode(&self, documents: Documents) -> Result<EmbeddedDocuments, Error> {
+    let sender = match &self.runner {
+      Runner::Thread(sender) => sender,
+      Runner::Daemon(socket) => {
+        let (info, embeddings) = daemon::embed(socket, fragment_texts(&documents)).await?;
+        return Ok(to_embedded(documents, embeddings, &info));
+      }
+    };
 
-      let average_time = total_time / *total_items as u128;
-      info!("Average time per document: {}ms", average_time);
-      info!("Total Items: {}", total_items);
+    let (result_sender, receiver) = oneshot::channel();
+    task::block_in_place(|| sender.send(Message::Documents(documents, result_sender)).map_err(|_| anyhow::anyhow!("Model runner stopped")))?;
+    receiver.await?
+  }
 
-      sender.send(documents.to_embedded(embedded_documents)).expect("sending results");
-    }
+  /// Embed plain texts, such as search queries
+  pub async fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, Error> {
+    let sender = match &self.runner {
+      Runner::Thread(sender) => sender,
+      Runner::Daemon(socket) => return Ok(daemon::embed(socket, texts).await?.1),
+    };
 
-    Ok(())
+    let (result_sender, receiver) = oneshot::channel();
+    task::block_in_place(|| sender.send(Message::Texts(texts, result_sender)).map_err(|_| anyhow::anyhow!("Model runner stopped")))?;
+    receiver.await?
   }
 
-  pub async fn encode(&self, documents: Documents) -> Result<EmbeddedDocuments, Error> {
-    let (sender, receiver) = oneshot::channel();
-    task::block_in_place(|| self.sender.send((documents, sender)))?;
+  pub async fn info(&self) -> Result<ModelInfo, Error> {
+    let sender = match &self.runner {
+      Runner::Thread(sender) => sender,
+      Runner::Daemon(socket) => return daemon::info(socket).await,
+    };
+
+    let (result_sender, receiver) = oneshot::channel();
+    task::block_in_place(|| sender.send(Message::Info(result_sender)).map_err(|_| anyhow::anyhow!("Model runner stopped")))?;
     Ok(receiver.await?)
   }
 }
 
-// --| Text Embedding -----------------
+// --| Embedding ----------------------
 // --|---------------------------------
-pub async fn text_embedding_async(text: String) -> Vec<f32> {
-  let handle = tokio::task::spawn_blocking(move ||  {
-    let embeds = get_text_embedding(&text);
-    embeds
-  });
-
-  let res = handle.await.unwrap();
-  res
+fn embed_documents(model: &dyn model::Model, batch_size: usize, documents: Documents) -> Result<EmbeddedDocuments, Error> {
+  let texts = fragment_texts(&documents);
+
+  let documents_start = Instant::now();
+  let embeddings = embed_batched(model, batch_size, &texts)?;
+  info!("Documents embedded in {:?}", documents_start.elapsed());
+
+  Ok(to_embedded(documents, embeddings, &ModelInfo::from_model(model)))
 }
 
-pub fn get_text_embedding(text: &str) -> Vec<f32> {
-  let model = SentenceEmbeddingsBuilder::remote(SentenceEmbeddingsModelType::AllMiniLmL12V2)
-    .create_model()
-    .expect("Could not create model");
-  
-  let embedding = model.encode(&[text.to_string()]).expect("Could not embed fragment");
-  embedding[0].clone()
+/// The texts to embed for every fragment, in document order
+fn fragment_texts(documents: &Documents) -> Vec<String> {
+  documents.documents.iter()
+    .flat_map(|document| document.fragments.iter())
+    .map(|fragment| fragment.embedding_text())
+    .collect()
 }
 
-// --| Documents ----------------------
-// --|---------------------------------
-// pub async fn _embedding_async(documents: Documents) -> EmbeddedDocuments {
-//   let handle = tokio::task::spawn_blocking(move ||  {
-//     let embeds = get_text_embedding(&documents);
-//     embeds
-//   });
-//
-//   let res = handle.await.unwrap();
-//   res
-// }
+/// Pair the fragments with their embeddings, in the order of `fragment_texts`
+fn to_embedded(documents: Documents, embeddings: Vec<Vec<f32>>, info: &ModelInfo) -> EmbeddedDocuments {
+  let embedded_documents = documents.documents.iter()
+    .flat_map(|document| document.fragments.iter())
+    .zip(embeddings)
+    .map(|(fragment, embedding)| fragment.to_embedded(embedding))
+    .collect::<Vec<_>>();
+
+  let mut embedded = documents.to_embedded(embedded_documents);
+  embedded.model = info.name.clone();
+  embedded.dimensions = info.dimensions;
+  embedded
+}
+
+/// Embed the texts in batches, returned in the same order as the input
+fn embed_batched(model: &dyn model::Model, batch_size: usize, texts: &[String]) -> Result<Vec<Vec<f32>>, Error> {
+  // --| Similar lengths are batched together to reduce padding
+  let mut order = (0..texts.len()).collect::<Vec<usize>>();
+  order.sort_by_key(|&i| texts[i].len());
+
+  let mut embeddings: Vec<Option<Vec<f32>>> = vec![None; texts.len()];
+  let mut batch_times = vec![];
+
+  for (batch_index, batch) in order.chunks(batch_size).enumerate() {
+    let batch_start = Instant::now();
+
+    let batch_texts = batch.iter().map(|&i| texts[i].clone()).collect::<Vec<String>>();
+    let batch_embeddings = model.encode(&batch_texts)
+      .map_err(|err| anyhow::anyhow!("Could not embed fragments: {}", err))?;
+
+    for (&i, embedding) in batch.iter().zip(batch_embeddings) {
+      embeddings[i] = Some(embedding);
+    }
+
+    let elapsed = batch_start.elapsed();
+    perf!("Batch {}: {} fragments in {:?}", batch_index, batch.len(), elapsed);
+    batch_times.push(elapsed);
+  }
+
+  let total_items = texts.len();
+  if total_items > 0 {
+    let total_time: u128 = batch_times.iter().map(|t| t.as_millis()).sum();
+    info!("Average time per fragment: {}ms", total_time / total_items as u128);
+    info!("Average time per batch: {}ms", total_time / batch_times.len() as u128);
+  }
+  info!("Total Items: {}, batch size: {}", total_items, batch_size);
+
+  embeddings.into_iter()
+    .map(|embedding| embedding.ok_or(anyhow::anyhow!("Fragment was not embedded")))
+    .collect()
+}
 
 fn _to_array(array: &[f32]) -> [f32; 1536] {
     array.try_into().expect("slice with incorrect length")
 }
-
@@ -1,59 +1,257 @@
-use std::env;
+use simplelog::*;
+use anyhow::Result;
 use std::path::PathBuf;
 use std::path::Path;
 use std::io::Write;
 
-use ignore::WalkBuilder;
-use crate::matcher::{Matcher,MatcherKind};
+use ignore::{Match, WalkBuilder};
+use ignore::gitignore::Gitignore;
+use path_absolutize::Absolutize;
+use std::collections::HashSet;
+use crate::matcher::{Matcher, RuleMatch};
 
+// Threads walking the project
+const WALKER_THREADS: usize = 6;
+
+// Per-directory ignore file for files that should only be kept out of the index
+pub const VECTORIZER_IGNORE: &str = ".vectorizerignore";
+
+// --| Ignore Files -------------------
+// --|---------------------------------
+/// The ignore files respected while walking the project, in this directory
+/// and its parents. Each is on by default and can be turned off in
+/// `[indexer]` or with its `--no-*` flag.
+#[derive(Debug, Clone, Copy)]
+pub struct IgnoreFiles {
+  pub gitignore: bool,
+  pub ignore: bool,
+  pub vectorizerignore: bool,
+}
+
+impl IgnoreFiles {
+  pub fn from_settings(settings: &config::Config) -> Self {
+    Self {
+      gitignore: settings.get_bool("indexer.gitignore").unwrap_or(true),
+      ignore: settings.get_bool("indexer.ignore_file").unwrap_or(true),
+      vectorizerignore: settings.get_bool("indexer.vectorizerignore").unwrap_or(true),
+    }
+  }
+
+  /// A walker over `path` that respects the enabled ignore files. The
+  /// .gitignore files apply outside of git repositories too.
+  pub fn walk_builder(&self, path: &Path) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(path);
+    builder
+      .git_ignore(self.gitignore)
+      .git_global(self.gitignore)
+      .git_exclude(self.gitignore)
+      .require_git(false)
+      .ignore(self.ignore)
+      .parents(true);
+
+    if self.vectorizerignore {
+      builder.add_custom_ignore_filename(VECTORIZER_IGNORE);
+    }
+
+    builder
+  }
+
+  /// The enabled ignore file names, in increasing order of precedence
+  fn file_names(&self) -> Vec<&'static str> {
+    let mut names = vec![];
+    if self.gitignore { names.push(".gitignore"); }
+    if self.ignore { names.push(".ignore"); }
+    if self.vectorizerignore { names.push(VECTORIZER_IGNORE); }
+    names
+  }
+
+  /// The ignore file and rule that excludes a path, searched from its
+  /// directory up. Global git excludes are not searched.
+  pub fn matching_rule(&self, path: &Path, is_dir: bool) -> Option<String> {
+    self.matching_rule_with(path, is_dir, |file| file.is_file().then(|| Gitignore::new(file).0))
+  }
+
+  /// `matching_rule` with the ignore files loaded by `load`, such as the
+  /// ones of a git revision
+  pub fn matching_rule_with(&self, path: &Path, is_dir: bool, load: impl Fn(&Path) -> Option<Gitignore>) -> Option<String> {
+    let path = path.absolutize().ok()?.to_path_buf();
+    let mut directory = path.parent();
+
+    while let Some(dir) = directory {
+      for name in self.file_names().iter().rev() {
+        let file = dir.join(name);
+        let ignore = match load(&file) {
+          Some(ignore) => ignore,
+          None => continue,
+        };
+
+        match ignore.matched_path_or_any_parents(&path, is_dir) {
+          Match::Ignore(glob) => return Some(format!("{} rule {}", file.display(), glob.original())),
+          Match::Whitelist(_) => return None,
+          Match::None => {},
+        }
+      }
+      directory = dir.parent();
+    }
+
+    None
+  }
+}
+
+// --| Verdict ------------------------
+// --|---------------------------------
+/// Whether a file is indexed, and the rule that decided it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+  Included,
+  IncludedByRule(String),
+  ExcludedByRule(String),
+  NoMatchingRule,
+  IgnoredDirectory(String),
+  Extension(String),
+  Hidden,
+  IgnoreFile(Option<String>),
+  FileSize(u64),
+  OutsideDirectories,
+}
+
+impl Verdict {
+  pub fn is_included(&self) -> bool {
+    matches!(self, Verdict::Included | Verdict::IncludedByRule(_))
+  }
+}
+
+impl std::fmt::Display for Verdict {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      Verdict::Included => write!(f, "included"),
+      Verdict::IncludedByRule(rule) => write!(f, "included by matcher rule {}", rule),
+      Verdict::ExcludedByRule(rule) => write!(f, "excluded by matcher rule {}", rule),
+      Verdict::NoMatchingRule => write!(f, "excluded, no matcher rule includes it"),
+      Verdict::IgnoredDirectory(dir) => write!(f, "excluded by ignored directory {}", dir),
+      Verdict::Extension(ext) => write!(f, "excluded by extension {}", if ext.is_empty() { "(none)" } else { ext }),
+      Verdict::Hidden => write!(f, "excluded, hidden"),
+      Verdict::IgnoreFile(Some(rule)) => write!(f, "excluded by {}", rule),
+      Verdict::IgnoreFile(None) => write!(f, "excluded by an ignore file"),
+      Verdict::FileSize(size) => write!(f, "excluded, {} bytes is above max_file_size", size),
+      Verdict::OutsideDirectories => write!(f, "excluded, outside of the indexed directories"),
+    }
+  }
+}
+
+// --| File Walker --------------------
+// --|---------------------------------
+/// Walks the project in parallel and decides for every file whether it is
+/// indexed. In order of precedence:
+///
+/// 1. Hidden files and files matched by the enabled ignore files are skipped
+/// 2. The last matcher rule matching the file decides, `!` rules exclude
+/// 3. If there are matcher rules that include files, other files are excluded
+/// 4. Files within an `ignored` directory are excluded
+/// 5. Files without one of the `extensions` are excluded, unless empty or `*`
+/// 6. Files larger than `max_file_size` are excluded, even when included by a rule
+///
+/// Only the `directories` are walked, or the whole project without them.
 pub struct FileWalker {
   pub path: PathBuf,
+  pub ignore_files: IgnoreFiles,
+  pub matcher: Matcher,
+  pub directories: Vec<PathBuf>,
+  pub extensions: Vec<String>,
+  pub ignored: Vec<String>,
+  pub max_file_size: u64,
+}
+
+// --| Candidate ----------------------
+// --|---------------------------------
+/// A path seen while explaining the walk. Excluded directories are not
+/// entered, so their files are not listed.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+  pub path: PathBuf,
+  pub directory: bool,
+  pub verdict: Verdict,
 }
 
 impl FileWalker {
-pub fn new(path: &PathBuf) -> Self {
-    Self { path: path.to_path_buf() }
+  pub fn new(path: &Path, ignore_files: IgnoreFiles) -> Self {
+    Self {
+      path: path.to_path_buf(),
+      ignore_files,
+      matcher: Matcher::default(),
+      directories: vec![],
+      extensions: vec![],
+      ignored: vec![],
+      max_file_size: 0,
+    }
   }
 
-  pub fn walk_files (&mut self, rules: &Vec<String>) -> Vec<DirEntry> {
-    let (tx, rx) = flume::unbounded();  
+  /// `matcher.rules` and the `extensions`, `directories` and `ignored` of
+  /// `[indexer]`. Directories are relative to the project root.
+  pub fn from_settings(path: &Path, settings: &config::Config) -> Result<Self> {
+    let list = |key: &str| settings.get::<Vec<String>>(key).unwrap_or_default();
 
-    let walker = WalkBuilder::new(&self.path)
-        .git_ignore(false)
-        .ignore(false)
-        .parents(false)
-        .threads(6)
-        .build_parallel();
+    let mut walker = Self::new(path, IgnoreFiles::from_settings(settings));
+    walker.matcher = Matcher::from_rules(path, &list("matcher.rules"))?;
+    walker.directories = list("indexer.directories").iter().map(|dir| path.join(dir)).collect();
+    walker.extensions = list("indexer.extensions");
+    walker.ignored = list("indexer.ignored").iter().map(|dir| dir.trim_matches('/').to_string()).collect();
+    walker.max_file_size = settings.get_int("indexer.max_file_size").unwrap_or(0).max(0) as u64;
+    Ok(walker)
+  }
+
+  /// The existing `directories`, or the project root
+  fn roots(&self) -> Vec<PathBuf> {
+    let mut roots = match self.directories.is_empty() {
+      true => vec![self.path.clone()],
+      false => self.directories.clone(),
+    };
+
+    roots.retain(|dir| {
+      if !dir.exists() { warn!("ERROR: {:?} does not exist", dir); }
+      dir.exists()
+    });
+    roots
+  }
+
+  /// The included files, sorted by path
+  pub fn walk_files (&self) -> Vec<DirEntry> {
+    let (tx, rx) = flume::unbounded();
+
+    let roots = self.roots();
+    if roots.is_empty() { return vec![]; }
 
-    // Include all files that start with ! exclimation mark
-    // let include_matches = rules.iter().filter(|x| x.starts_with("!")).map(|x| format!("{}", x)).collect::<Vec<_>>();
-    
-    // Exclude all files that don't start with ! exclimation mark
-    // let exclude_matches = rules.iter().filter(|x| !x.starts_with("!")).map(|x| format!("{}", x)).collect::<Vec<_>>();
-     
-    
-    // println!("Includes: {:?}", include_matches);
-    // println!("Excludes: {:?}", exclude_matches);
+    let mut builder = self.ignore_files.walk_builder(&roots[0]);
+    for root in &roots[1..] { builder.add(root); }
+
+    // --| Nothing can include files within ignored directories without matcher includes
+    if !self.matcher.has_includes() && !self.ignored.is_empty() {
+      let root = self.path.clone();
+      let ignored = self.ignored.clone();
+      builder.filter_entry(move |entry| {
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        !is_dir || ignored_directory(&relative(&root, entry.path()), &ignored).is_none()
+      });
+    }
+
+    let walker = builder
+        .threads(WALKER_THREADS)
+        .build_parallel();
 
     walker.run(|| {
       let tx = tx.clone();
-      let matcher = 
-        Matcher::new(
-          MatcherKind::Exclude(
-            Matcher::create_matcher(&env::current_dir().unwrap(), rules.to_vec()).unwrap()
-        ));
 
-      
       Box::new(move |result| {
         let dent = match result {
           Ok(dent) => { dent },
           Err(err) => {
-            eprintln!("{}", err);
+            warn!("{}", err);
             return ignore::WalkState::Continue;
           }
         };
-        
-        if matcher.should_include(&dent.path()) {
+
+        let is_file = dent.file_type().map(|t| t.is_file()).unwrap_or(false);
+        if is_file && self.verdict(dent.path()).is_included() {
           tx.send(DirEntry::X(dent)).unwrap();
         }
 
@@ -62,10 +260,162 @@ pub fn new(path: &PathBuf) -> Self {
     });
 
     drop(tx);
-    rx.drain().collect::<Vec<_>>()
+    let mut files = rx.drain().collect::<Vec<_>>();
+    files.sort_by(|a, b| a.path().cmp(b.path()));
+    files
+  }
+
+  /// Every path in the walked directories with its verdict, sorted by path.
+  /// Paths hidden or ignored by ignore files are listed with the reason.
+  pub fn explain(&self) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    for root in self.roots() {
+      // --| The paths the ignore files and hidden rule let through
+      let visible = self.ignore_files.walk_builder(&root).build()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().to_path_buf())
+        .collect::<HashSet<PathBuf>>();
+
+      self.explain_directory(&root, &visible, &mut candidates);
+    }
+
+    candidates
+  }
+
+  fn explain_directory(&self, directory: &Path, visible: &HashSet<PathBuf>, candidates: &mut Vec<Candidate>) {
+    let mut entries = match std::fs::read_dir(directory) {
+      Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect::<Vec<PathBuf>>(),
+      Err(err) => {
+        warn!("ERROR: {:?}: {}", directory, err);
+        return;
+      }
+    };
+    entries.sort();
+
+    for path in entries {
+      let is_dir = path.is_dir();
+      let hidden = path.file_name().and_then(|n| n.to_str()).map(|n| n.starts_with('.')).unwrap_or(false);
+
+      let verdict = if !visible.contains(&path) {
+        match hidden {
+          true => Verdict::Hidden,
+          false => Verdict::IgnoreFile(self.ignore_files.matching_rule(&path, is_dir)),
+        }
+      } else if is_dir {
+        match self.matcher.has_includes() {
+          true => None,
+          false => ignored_directory(&relative(&self.path, &path), &self.ignored).map(Verdict::IgnoredDirectory),
+        }.unwrap_or(Verdict::Included)
+      } else {
+        self.verdict(&path)
+      };
+
+      let enter = is_dir && verdict == Verdict::Included;
+      if !is_dir || !enter {
+        candidates.push(Candidate { path: path.clone(), directory: is_dir, verdict });
+      }
+
+      if enter { self.explain_directory(&path, visible, candidates); }
+    }
+  }
+
+  /// The verdict for a single file, as if the walk had reached it. Files
+  /// outside the walked directories, hidden or matched by an ignore file
+  /// are excluded too.
+  pub fn file_verdict(&self, path: &Path) -> Verdict {
+    let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    self.path_verdict(&relative(&self.path, path), size, |path| self.ignore_files.matching_rule(path, false))
+  }
+
+  /// `file_verdict` for a file that need not be on disk, such as one of a
+  /// git revision, by its path relative to the project root. `ignore_rule`
+  /// returns the ignore file rule that excludes a path, if any.
+  pub fn path_verdict(&self, relative_path: &Path, size: u64, ignore_rule: impl Fn(&Path) -> Option<String>) -> Verdict {
+    let path = self.path.join(relative_path);
+
+    if !self.directories.is_empty() && !self.directories.iter().any(|dir| path.starts_with(dir)) {
+      return Verdict::OutsideDirectories;
+    }
+
+    let hidden = relative_path.components()
+      .filter_map(|c| c.as_os_str().to_str())
+      .any(|name| name.starts_with('.'));
+    if hidden { return Verdict::Hidden; }
+
+    if let Some(rule) = ignore_rule(&path) {
+      return Verdict::IgnoreFile(Some(rule));
+    }
+
+    let verdict = self.rule_verdict(&path);
+    if verdict.is_included() && self.max_file_size > 0 && size > self.max_file_size {
+      return Verdict::FileSize(size);
+    }
+
+    verdict
+  }
+
+  /// The verdict of the matcher rules, ignored directories, extensions and
+  /// file size
+  pub fn verdict(&self, path: &Path) -> Verdict {
+    let verdict = self.rule_verdict(path);
+
+    if verdict.is_included() && self.max_file_size > 0 {
+      let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+      if size > self.max_file_size { return Verdict::FileSize(size); }
+    }
+
+    verdict
+  }
+
+  fn rule_verdict(&self, path: &Path) -> Verdict {
+    let relative = relative(&self.path, path);
+
+    match self.matcher.matched(&relative) {
+      RuleMatch::Include(rule) => return Verdict::IncludedByRule(rule),
+      RuleMatch::Exclude(rule) => return Verdict::ExcludedByRule(rule),
+      RuleMatch::None if self.matcher.has_includes() => return Verdict::NoMatchingRule,
+      RuleMatch::None => {},
+    }
+
+    if let Some(dir) = ignored_directory(relative.parent().unwrap_or(Path::new("")), &self.ignored) {
+      return Verdict::IgnoredDirectory(dir);
+    }
+
+    if !self.extensions.is_empty() && !self.extensions.iter().any(|e| e == "*") {
+      let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+      if !self.extensions.iter().any(|e| e == extension) {
+        return Verdict::Extension(extension.to_string());
+      }
+    }
+
+    Verdict::Included
   }
 }
 
+// --| Helper functions ---------------
+// --|---------------------------------
+/// The path relative to the project root, as is if it is outside of it
+fn relative(root: &Path, path: &Path) -> PathBuf {
+  path.strip_prefix(root).unwrap_or(path).to_path_buf()
+}
+
+/// The ignored entry a directory, relative to the project root, falls under.
+/// Names match any directory in the path (`target`), paths match from the
+/// project root (`docs/build`).
+fn ignored_directory(directory: &Path, ignored: &[String]) -> Option<String> {
+  let names = directory.components()
+    .filter_map(|c| c.as_os_str().to_str())
+    .collect::<Vec<&str>>();
+
+  ignored.iter().find(|dir| {
+    match dir.contains('/') {
+      true => directory.starts_with(dir.as_str()),
+      false => names.contains(&dir.as_str()),
+    }
+  }).cloned()
+}
+
 pub enum DirEntry {
   X(ignore::DirEntry),
 }
@@ -0,0 +1,135 @@
+use simplelog::*;
+use anyhow::{anyhow, Error};
+use std::sync::mpsc;
+use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeSet;
+use tokio::task;
+use notify::RecursiveMode;
+use notify_debouncer_mini::new_debouncer;
+
+use crate::indexer;
+use crate::SETTINGS;
+use crate::vectorize::Model;
+use crate::walker::FileWalker;
+use crate::database::Database;
+use crate::output::{OutputFormat, print_item};
+use crate::manifest::{Manifest, relative_path};
+use crate::upload::{self, UploadOptions, UploadSummary, print_summary};
+
+// Quiet time after the last change before the changed files are uploaded
+pub const DEFAULT_DEBOUNCE_MS: u64 = 500;
+
+// --| Watch Options ------------------
+// --|---------------------------------
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+  pub upload: UploadOptions,
+  pub debounce: Duration,
+}
+
+// --| Watch --------------------------
+// --|---------------------------------
+/// Upload the changes since the last upload, then keep the collection in
+/// sync with the project until interrupted. The model stays loaded, and
+/// after each burst of changes only the files that changed are embedded
+/// again. Deleted files, and files that are no longer selected, have their
+/// points removed.
+pub async fn watch(database: &dyn Database, options: WatchOptions, output: OutputFormat) -> Result<(), Error> {
+  let root = {
+    let settings = SETTINGS.read().unwrap();
+    if settings.get_bool("indexer.is_file").unwrap_or(false) {
+      return Err(anyhow!("Watch needs a project directory, not a single file"));
+    }
+    PathBuf::from(settings.get_str("indexer.root")?)
+  };
+
+  let model = Model::load();
+  let summary = upload::upload_with(database, Some(&model), options.upload).await?;
+  print_item(output, &summary, print_summary);
+
+  // --| Events are debounced, a save often touches a file several times
+  let (sender, receiver) = mpsc::channel();
+  let mut debouncer = new_debouncer(options.debounce, None, sender)?;
+  debouncer.watcher().watch(&root, RecursiveMode::Recursive)?;
+  info!("Watching {} for changes", root.display());
+
+  while let Ok(result) = task::block_in_place(|| receiver.recv()) {
+    let events = match result {
+      Ok(events) => events,
+      Err(errors) => {
+        for err in errors { warn!("Watch error: {}", err); }
+        continue;
+      }
+    };
+
+    let paths = events.into_iter().map(|event| event.path).collect::<BTreeSet<PathBuf>>();
+    match upload_changes(database, &model, &root, paths).await {
+      Ok(Some(summary)) => print_item(output, &summary, print_summary),
+      Ok(None) => {},
+      Err(err) => error!("Could not upload the changes: {}", err),
+    }
+  }
+
+  Ok(())
+}
+
+// --| Helper functions ---------------
+// --|---------------------------------
+/// Re-index the changed files the walker selects, and remove the points of
+/// the ones that were deleted or are no longer selected. None if none of
+/// the changes concern the index.
+async fn upload_changes(database: &dyn Database, model: &Model, root: &Path, paths: BTreeSet<PathBuf>) -> Result<Option<UploadSummary>, Error> {
+  let (mut manifest, files, missing) = {
+    let settings = SETTINGS.read().unwrap();
+    let manifest = Manifest::load(&settings);
+    let walker = FileWalker::from_settings(root, &settings)?;
+
+    let mut files: Vec<PathBuf> = vec![];
+    let mut missing: Vec<String> = vec![];
+
+    for path in paths {
+      let relative = relative_path(&path, root);
+      let prefix = format!("{}/", relative);
+      let tracked = |file: &String| *file == relative || file.starts_with(&prefix);
+
+      // --| A directory that was moved in is walked as a whole, its own
+      // --| location can still be hidden or ignored
+      if path.is_dir() {
+        if path == root || manifest.files.keys().any(tracked) { continue; }
+
+        let mut directory = FileWalker::from_settings(root, &settings)?;
+        directory.directories = vec![path];
+        files.extend(
+          directory.walk_files().iter()
+            .map(|entry| entry.path().to_path_buf())
+            .filter(|file| walker.file_verdict(file).is_included())
+        );
+        continue;
+      }
+
+      if path.is_file() {
+        let verdict = walker.file_verdict(&path);
+        if verdict.is_included() { files.push(path); continue; }
+        debug!("Not indexed: {}: {}", path.display(), verdict);
+      }
+
+      // --| Gone or excluded, a deleted directory takes its files with it
+      missing.extend(manifest.files.keys().filter(|file| tracked(file)).cloned());
+    }
+
+    files.sort();
+    files.dedup();
+    (manifest, files, missing)
+  };
+
+  if files.is_empty() && missing.is_empty() { return Ok(None); }
+  for path in &missing { info!("Removing: {}", path); }
+
+  let documents = indexer::index_paths(&files, Some(&manifest));
+  if documents.documents.is_empty() && missing.is_empty() { return Ok(None); }
+
+  let options = UploadOptions { force: false, prune: true };
+  let summary = upload::upload_documents(database, Some(model), &mut manifest, documents, missing, &options).await?;
+  Ok(Some(summary))
+}